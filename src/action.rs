@@ -0,0 +1,248 @@
+//! Operations applied to duplicate groups once a search is complete
+
+mod delete;
+mod link;
+mod reflink;
+
+use std::{
+    fs,
+    fs::File,
+    io,
+    io::prelude::*,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use clap::ArgEnum;
+use log::{error, info};
+
+pub use self::{
+    delete::{delete, Keep, Keeper},
+    link::link,
+};
+use crate::{
+    hash::HashMap,
+    report::{Entry, Group, Kind},
+    Result,
+};
+
+/// An operation to perform on every group of duplicate files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum Action {
+    /// Replace duplicates with hardlinks to a single copy
+    Link,
+    /// Share the data of duplicates on copy-on-write filesystems, keeping them
+    /// as separate files
+    Reflink,
+    /// Delete all but one copy, chosen according to `--keep` and
+    /// `--keep-under`
+    Delete,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    pub dry_run: bool,
+    pub block_size: usize,
+}
+
+/// The effect of an action on a single group
+#[derive(Debug, Default, Clone, Copy)]
+struct Outcome {
+    files: usize,
+    bytes: u64,
+    /// Number of files that could not be processed
+    failed: usize,
+}
+
+impl std::ops::AddAssign for Outcome {
+    fn add_assign(&mut self, rhs: Self) {
+        self.files += rhs.files;
+        self.bytes += rhs.bytes;
+        self.failed += rhs.failed;
+    }
+}
+
+/// Counts the paths to each inode in a group, since the space used by an inode
+/// is only freed once every path to it has been removed
+#[derive(Debug)]
+pub struct Links(HashMap<(u64, u64), usize>);
+
+impl Links {
+    pub fn new<'a>(entries: impl IntoIterator<Item = &'a Entry>) -> Self {
+        let mut links = HashMap::default();
+
+        for entry in entries {
+            *links.entry((entry.device, entry.inode)).or_default() += 1;
+        }
+
+        Self(links)
+    }
+
+    /// The number of paths to an entry's inode that have not been removed
+    fn count(&self, entry: &Entry) -> usize {
+        self.0.get(&(entry.device, entry.inode)).copied().unwrap_or(0)
+    }
+
+    /// Record that the path of an entry no longer points to its inode,
+    /// returning the number of bytes this freed
+    pub fn remove(&mut self, entry: &Entry) -> u64 {
+        match self.0.get_mut(&(entry.device, entry.inode)) {
+            Some(n) if *n > 1 => {
+                *n -= 1;
+                0
+            },
+            Some(n) => {
+                *n = 0;
+                entry.size
+            },
+            None => 0,
+        }
+    }
+}
+
+/// Group the entries of a group by the device containing them, since files
+/// can only share data with other files on the same device
+fn by_device(files: &[Entry]) -> HashMap<u64, Vec<&Entry>> {
+    let mut by_device: HashMap<u64, Vec<&Entry>> = HashMap::default();
+
+    for entry in files {
+        by_device.entry(entry.device).or_default().push(entry);
+    }
+
+    by_device
+}
+
+/// Apply an action to every group of duplicate files.  Failures are reported
+/// per group and do not stop the remaining groups from being processed.
+/// Returns the number of files and groups that could not be processed.
+pub fn run(action: Action, groups: &[Group], opts: Options, keeper: &Keeper) -> usize {
+    let mut total = Outcome::default();
+
+    for group in groups
+        .iter()
+        .filter(|g| g.kind == Kind::File && g.copies > 1)
+    {
+        let res = match action {
+            Action::Link => link::apply(group, opts),
+            Action::Reflink => reflink::apply(group, opts),
+            Action::Delete => delete::apply(group, opts, keeper),
+        };
+
+        match res {
+            Ok(o) => total += o,
+            Err(e) => {
+                error!("Failed to process group {}: {:?}", group.hash, e);
+                total.failed += 1;
+            },
+        }
+    }
+
+    let (verb, effect) = match (action, opts.dry_run) {
+        (Action::Link, false) => ("Linked", "freeing"),
+        (Action::Link, true) => ("Would link", "freeing"),
+        (Action::Reflink, false) => ("Deduplicated", "sharing"),
+        (Action::Reflink, true) => ("Would deduplicate", "sharing up to"),
+        (Action::Delete, false) => ("Deleted", "freeing"),
+        (Action::Delete, true) => ("Would delete", "freeing"),
+    };
+
+    eprintln!(
+        "{} {} file(s), {} {} byte(s)",
+        verb, total.files, effect, total.bytes
+    );
+
+    total.failed
+}
+
+/// Ensure a file has not been changed since it was scanned
+pub fn check_unchanged(entry: &Entry) -> Result {
+    let meta = fs::symlink_metadata(&entry.path)
+        .with_context(|| format!("Failed to stat {:?}", entry.path))?;
+
+    if !meta.is_file()
+        || meta.dev() != entry.device
+        || meta.ino() != entry.inode
+        || meta.len() != entry.size
+        || meta.mtime() != entry.mtime
+        || meta.mtime_nsec() != entry.mtime_nsec
+    {
+        bail!("{:?} has changed since it was scanned", entry.path);
+    }
+
+    Ok(())
+}
+
+/// Compare the full contents of two files byte-for-byte
+fn same_contents(a: &Path, b: &Path, block_size: usize) -> Result<bool> {
+    fn open(path: &Path) -> Result<File> {
+        File::open(path).with_context(|| format!("Failed to open {:?}", path))
+    }
+
+    let (mut a_file, mut b_file) = (open(a)?, open(b)?);
+    let (mut a_buf, mut b_buf) = (vec![0_u8; block_size], vec![0_u8; block_size]);
+
+    loop {
+        let n = read_full(&mut a_file, &mut a_buf)
+            .with_context(|| format!("Failed to read {:?}", a))?;
+        let m = read_full(&mut b_file, &mut b_buf)
+            .with_context(|| format!("Failed to read {:?}", b))?;
+
+        if a_buf[..n] != b_buf[..m] {
+            return Ok(false);
+        }
+
+        if n == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// Fill as much of `buf` as possible, stopping early only at end-of-file
+fn read_full(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+
+    while len < buf.len() {
+        match file.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(len)
+}
+
+/// A path next to `path` to stage its replacement in before renaming it into
+/// place
+fn temp_path(path: &Path) -> Result<PathBuf> {
+    let name = path
+        .file_name()
+        .with_context(|| format!("{:?} has no file name", path))?;
+    let mut tmp = std::ffi::OsString::from(".");
+    tmp.push(name);
+    tmp.push(".latke~");
+
+    Ok(path.with_file_name(tmp))
+}
+
+/// Atomically replace `path` with the file staged at `tmp` by `stage`,
+/// cleaning up the staged file on failure
+fn replace_with(path: &Path, stage: impl FnOnce(&Path) -> Result) -> Result {
+    let tmp = temp_path(path)?;
+
+    stage(&tmp)?;
+
+    fs::rename(&tmp, path)
+        .with_context(|| format!("Failed to move {:?} into place", tmp))
+        .inspect_err(|_| {
+            if let Err(e) = fs::remove_file(&tmp) {
+                error!("Failed to clean up {:?}: {:?}", tmp, e);
+            }
+        })?;
+
+    info!("Replaced {:?}", path);
+
+    Ok(())
+}
@@ -0,0 +1,206 @@
+use std::{cmp::Ordering, fs, path::PathBuf};
+
+use anyhow::{bail, Context};
+use clap::ArgEnum;
+use log::{error, info};
+
+use super::{Links, Options, Outcome};
+use crate::{
+    report::{Entry, Group},
+    Result,
+};
+
+/// Rule for choosing which file in a group survives deletion
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum Keep {
+    /// Keep the file with the oldest modification time
+    Oldest,
+    /// Keep the file with the newest modification time
+    Newest,
+    /// Keep the file with the shortest path
+    ShortestPath,
+    /// Keep the file with the longest path
+    LongestPath,
+    /// Keep the file under the earliest search path given on the command line
+    FirstRoot,
+}
+
+/// Everything needed to choose the file to keep from a group
+#[derive(Debug)]
+pub struct Keeper {
+    pub rule: Keep,
+    /// Directories whose files are preferred over all others, in order of
+    /// priority
+    pub prefixes: Vec<PathBuf>,
+    /// The search paths, in the order they were given
+    pub roots: Vec<PathBuf>,
+}
+
+impl Keeper {
+    /// Choose the file to keep, preferring files under the highest-priority
+    /// prefix and then applying the rule.  Entries are sorted by path, so
+    /// remaining ties go to the first path.
+    fn choose<'a>(&self, files: &'a [Entry]) -> &'a Entry {
+        files
+            .iter()
+            .min_by(|a, b| {
+                Self::rank(&self.prefixes, a)
+                    .cmp(&Self::rank(&self.prefixes, b))
+                    .then_with(|| self.compare(a, b))
+            })
+            .unwrap()
+    }
+
+    fn compare(&self, a: &Entry, b: &Entry) -> Ordering {
+        let mtime = |e: &Entry| (e.mtime, e.mtime_nsec);
+        let len = |e: &Entry| e.path.as_os_str().len();
+
+        match self.rule {
+            Keep::Oldest => mtime(a).cmp(&mtime(b)),
+            Keep::Newest => mtime(b).cmp(&mtime(a)),
+            Keep::ShortestPath => len(a).cmp(&len(b)),
+            Keep::LongestPath => len(b).cmp(&len(a)),
+            Keep::FirstRoot => Self::rank(&self.roots, a).cmp(&Self::rank(&self.roots, b)),
+        }
+    }
+
+    /// The index of the first directory containing an entry, or the number of
+    /// directories if none do
+    fn rank(dirs: &[PathBuf], entry: &Entry) -> usize {
+        dirs.iter()
+            .position(|d| entry.path.starts_with(d))
+            .unwrap_or(dirs.len())
+    }
+}
+
+/// Delete every file in a group except the one chosen by the keeper rules,
+/// along with any other paths linked to it.  If the kept file cannot be
+/// verified, nothing in the group is deleted.
+pub fn apply(group: &Group, opts: Options, keeper: &Keeper) -> Result<Outcome> {
+    let keep = keeper.choose(&group.files);
+
+    super::check_unchanged(keep)?;
+
+    let mut links = Links::new(&group.files);
+    let mut outcome = Outcome::default();
+
+    for entry in group
+        .files
+        .iter()
+        .filter(|e| (e.device, e.inode) != (keep.device, keep.inode))
+    {
+        match delete(keep, entry, opts) {
+            Ok(()) => {
+                outcome.files += 1;
+                outcome.bytes += links.remove(entry);
+            },
+            Err(e) => {
+                error!("Failed to delete {:?}: {:?}", entry.path, e);
+                outcome.failed += 1;
+            },
+        }
+    }
+
+    Ok(outcome)
+}
+
+/// Delete `dup` after verifying it is unchanged and identical to `keep`
+pub fn delete(keep: &Entry, dup: &Entry, opts: Options) -> Result {
+    super::check_unchanged(dup)?;
+
+    // Reading the kept file here also ensures it still exists immediately
+    // before its duplicate is removed
+    if !super::same_contents(&keep.path, &dup.path, opts.block_size)? {
+        bail!("Contents differ from {:?}", keep.path);
+    }
+
+    if opts.dry_run {
+        eprintln!("Would delete {:?}, keeping {:?}", dup.path, keep.path);
+        return Ok(());
+    }
+
+    fs::remove_file(&dup.path).with_context(|| format!("Failed to remove {:?}", dup.path))?;
+
+    info!("Deleted {:?}", dup.path);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, mtime: i64) -> Entry {
+        Entry {
+            path: path.into(),
+            size: 1,
+            mtime,
+            mtime_nsec: 0,
+            inode: 0,
+            device: 0,
+        }
+    }
+
+    fn keeper(rule: Keep, prefixes: &[&str], roots: &[&str]) -> Keeper {
+        Keeper {
+            rule,
+            prefixes: prefixes.iter().map(PathBuf::from).collect(),
+            roots: roots.iter().map(PathBuf::from).collect(),
+        }
+    }
+
+    fn chosen<'a>(keeper: &Keeper, files: &'a [Entry]) -> &'a str {
+        keeper.choose(files).path.to_str().unwrap()
+    }
+
+    #[test]
+    fn rules() {
+        let files = [entry("a/long/x", 2), entry("b/x", 3), entry("c/xx", 1)];
+        let roots = ["c", "b", "a"];
+
+        assert_eq!(chosen(&keeper(Keep::Oldest, &[], &roots), &files), "c/xx");
+        assert_eq!(chosen(&keeper(Keep::Newest, &[], &roots), &files), "b/x");
+        assert_eq!(chosen(&keeper(Keep::ShortestPath, &[], &roots), &files), "b/x");
+        assert_eq!(chosen(&keeper(Keep::LongestPath, &[], &roots), &files), "a/long/x");
+        assert_eq!(chosen(&keeper(Keep::FirstRoot, &[], &roots), &files), "c/xx");
+    }
+
+    #[test]
+    fn ties_go_to_first_path() {
+        let files = [entry("a/x", 1), entry("b/x", 1), entry("c/x", 1)];
+
+        for rule in [Keep::Oldest, Keep::Newest, Keep::ShortestPath, Keep::LongestPath] {
+            assert_eq!(chosen(&keeper(rule, &[], &[]), &files), "a/x");
+        }
+
+        // Files outside every root all rank last
+        assert_eq!(chosen(&keeper(Keep::FirstRoot, &[], &["d"]), &files), "a/x");
+    }
+
+    #[test]
+    fn keep_under_takes_priority() {
+        let files = [entry("a/x", 1), entry("b/x", 2), entry("c/x", 3)];
+
+        let k = keeper(Keep::Oldest, &["c"], &[]);
+        assert_eq!(chosen(&k, &files), "c/x");
+
+        // Earlier prefixes win over later ones regardless of the rule
+        let k = keeper(Keep::Oldest, &["c", "b"], &[]);
+        assert_eq!(chosen(&k, &files), "c/x");
+        let k = keeper(Keep::Newest, &["b", "c"], &[]);
+        assert_eq!(chosen(&k, &files), "b/x");
+
+        // The rule still decides between files under the same prefix
+        let files = [entry("a/x", 1), entry("b/x", 2), entry("b/y", 1)];
+        let k = keeper(Keep::Oldest, &["b"], &[]);
+        assert_eq!(chosen(&k, &files), "b/y");
+    }
+
+    #[test]
+    fn prefixes_match_whole_components() {
+        let files = [entry("ab/x", 1), entry("a/x", 2)];
+        let k = keeper(Keep::Oldest, &["a"], &[]);
+
+        assert_eq!(chosen(&k, &files), "a/x");
+    }
+}
@@ -0,0 +1,65 @@
+use std::fs;
+
+use anyhow::{bail, Context};
+use log::error;
+
+use super::{Links, Options, Outcome};
+use crate::{
+    report::{Entry, Group},
+    Result,
+};
+
+/// Replace every file in a group with a hardlink to one copy per device.  The
+/// copy with the most existing links is kept, so that as few files as
+/// possible need to be replaced.
+pub fn apply(group: &Group, opts: Options) -> Result<Outcome> {
+    let mut outcome = Outcome::default();
+
+    for entries in super::by_device(&group.files).values() {
+        let mut links = Links::new(entries.iter().copied());
+
+        // Entries are sorted by path, so ties go to the first path
+        let keep = entries
+            .iter()
+            .copied()
+            .max_by(|a, b| links.count(a).cmp(&links.count(b)).then(b.path.cmp(&a.path)))
+            .unwrap();
+
+        super::check_unchanged(keep)?;
+
+        for entry in entries.iter().filter(|e| e.inode != keep.inode) {
+            match link(keep, entry, opts) {
+                Ok(()) => {
+                    outcome.files += 1;
+                    outcome.bytes += links.remove(entry);
+                },
+                Err(e) => {
+                    error!("Failed to link {:?}: {:?}", entry.path, e);
+                    outcome.failed += 1;
+                },
+            }
+        }
+    }
+
+    Ok(outcome)
+}
+
+/// Replace `dup` with a hardlink to `keep` after verifying it is unchanged and
+/// identical to it
+pub fn link(keep: &Entry, dup: &Entry, opts: Options) -> Result {
+    super::check_unchanged(dup)?;
+
+    if !super::same_contents(&keep.path, &dup.path, opts.block_size)? {
+        bail!("Contents differ from {:?}", keep.path);
+    }
+
+    if opts.dry_run {
+        eprintln!("Would link {:?} to {:?}", dup.path, keep.path);
+        return Ok(());
+    }
+
+    super::replace_with(&dup.path, |tmp| {
+        fs::hard_link(&keep.path, tmp)
+            .with_context(|| format!("Failed to link {:?} to {:?}", tmp, keep.path))
+    })
+}
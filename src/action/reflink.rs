@@ -0,0 +1,166 @@
+use std::{
+    cmp,
+    fs::{File, OpenOptions},
+    io,
+    os::unix::io::AsRawFd,
+};
+
+use anyhow::{anyhow, Context};
+use log::error;
+
+use super::{Options, Outcome};
+use crate::{
+    hash::HashSet,
+    report::{Entry, Group},
+    Result,
+};
+
+/// `_IOWR(0x94, 54, struct file_dedupe_range)` from `linux/fs.h`
+#[allow(clippy::cast_possible_wrap, clippy::cast_lossless)]
+const FIDEDUPERANGE: libc::Ioctl = 0xC018_9436_u32 as _;
+
+/// `FILE_DEDUPE_RANGE_DIFFERS` from `linux/fs.h`
+const DEDUPE_RANGE_DIFFERS: i32 = 1;
+
+/// Maximum number of bytes to request per call.  Filesystems may process less
+/// than this at a time anyway.
+const CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+
+/// `struct file_dedupe_range_info` from `linux/fs.h`
+#[repr(C)]
+#[derive(Debug, Default)]
+struct DedupeRangeInfo {
+    dest_fd: i64,
+    dest_offset: u64,
+    bytes_deduped: u64,
+    status: i32,
+    reserved: u32,
+}
+
+/// `struct file_dedupe_range` from `linux/fs.h`, with room for exactly one
+/// destination
+#[repr(C)]
+#[derive(Debug, Default)]
+struct DedupeRange {
+    src_offset: u64,
+    src_length: u64,
+    dest_count: u16,
+    reserved1: u16,
+    reserved2: u32,
+    info: [DedupeRangeInfo; 1],
+}
+
+/// Share the extents of one copy per device with every other copy in a group,
+/// leaving each path an independent file.  The kernel compares the contents
+/// of each range itself before sharing it.
+pub fn apply(group: &Group, opts: Options) -> Result<Outcome> {
+    let mut outcome = Outcome::default();
+
+    for entries in super::by_device(&group.files).values() {
+        let src = entries[0];
+        super::check_unchanged(src)?;
+        let src_file =
+            File::open(&src.path).with_context(|| format!("Failed to open {:?}", src.path))?;
+        let mut done = HashSet::default();
+        done.insert(src.inode);
+
+        for entry in entries {
+            if !done.insert(entry.inode) || entry.size == 0 {
+                continue;
+            }
+
+            if let Err(e) = super::check_unchanged(entry) {
+                error!("Skipping {:?}: {:?}", entry.path, e);
+                outcome.failed += 1;
+                continue;
+            }
+
+            if opts.dry_run {
+                eprintln!("Would deduplicate {:?} against {:?}", entry.path, src.path);
+                outcome.files += 1;
+                outcome.bytes += entry.size;
+                continue;
+            }
+
+            match dedupe(&src_file, entry) {
+                Ok(n) => {
+                    outcome.files += 1;
+                    outcome.bytes += n;
+                },
+                Err(e)
+                    if matches!(
+                        e.raw_os_error(),
+                        Some(libc::EOPNOTSUPP | libc::ENOTTY | libc::EXDEV)
+                    ) =>
+                {
+                    return Err(anyhow!(e)).with_context(|| {
+                        format!(
+                            "The filesystem containing {:?} does not support extent deduplication",
+                            src.path
+                        )
+                    });
+                },
+                Err(e) => {
+                    error!(
+                        "Failed to deduplicate {:?} against {:?}: {:?}",
+                        entry.path, src.path, e
+                    );
+                    outcome.failed += 1;
+                },
+            }
+        }
+    }
+
+    Ok(outcome)
+}
+
+/// Deduplicate the whole of `dest` against `src`, returning the number of
+/// bytes shared
+fn dedupe(src: &File, dest: &Entry) -> io::Result<u64> {
+    let dest_file = OpenOptions::new().write(true).open(&dest.path)?;
+    let mut offset = 0;
+
+    while offset < dest.size {
+        let mut range = DedupeRange {
+            src_offset: offset,
+            src_length: cmp::min(CHUNK_SIZE, dest.size - offset),
+            dest_count: 1,
+            info: [DedupeRangeInfo {
+                dest_fd: dest_file.as_raw_fd().into(),
+                dest_offset: offset,
+                ..DedupeRangeInfo::default()
+            }],
+            ..DedupeRange::default()
+        };
+
+        // SAFETY: range is a valid file_dedupe_range followed by exactly
+        //         dest_count info entries, and both descriptors are open
+        if unsafe { libc::ioctl(src.as_raw_fd(), FIDEDUPERANGE, &mut range) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let info = &range.info[0];
+
+        match info.status {
+            0 => (),
+            DEDUPE_RANGE_DIFFERS => {
+                return Err(io::Error::other(format!(
+                    "Contents differ at offset {}",
+                    offset
+                )));
+            },
+            s => return Err(io::Error::from_raw_os_error(-s)),
+        }
+
+        if info.bytes_deduped == 0 {
+            return Err(io::Error::other(format!(
+                "No progress made at offset {}",
+                offset
+            )));
+        }
+
+        offset += info.bytes_deduped;
+    }
+
+    Ok(offset)
+}
@@ -0,0 +1,322 @@
+//! Persistent storage for file hashes from previous runs
+
+use std::{
+    ffi::OsString,
+    fs,
+    fs::{File, OpenOptions},
+    io,
+    io::{BufRead, BufReader, BufWriter, Write},
+    mem,
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::MetadataExt,
+    },
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use log::{error, info, trace, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    digest::{Algorithm, Hash},
+    hash::{DashMap, DashSet},
+    Meta, Result,
+};
+
+/// Bumped whenever the layout of the cache file changes
+const VERSION: u32 = 3;
+
+/// Minimum time between periodic flushes of the cache file
+const FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The file attributes a cached hash is valid for.  If any of these change,
+/// the file is assumed to have been modified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Key {
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    ctime: i64,
+    ctime_nsec: i64,
+    inode: u64,
+    device: u64,
+}
+
+impl Key {
+    fn new(meta: &Meta) -> Self {
+        Self {
+            size: meta.len(),
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec(),
+            ctime: meta.ctime(),
+            ctime_nsec: meta.ctime_nsec(),
+            inode: meta.ino(),
+            device: meta.dev(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    key: Key,
+    algorithm: Algorithm,
+    hash: Hash,
+}
+
+/// Hashes from previous runs, keyed by path.
+///
+/// The cache file holds a version number followed by a stream of
+/// `(path bytes, entry)` records, where later records for a path replace
+/// earlier ones.  Periodic flushes only append the entries added since the
+/// last flush, and the final flush rewrites the file without duplicates.
+#[derive(Debug)]
+pub struct Cache {
+    path: PathBuf,
+    entries: DashMap<PathBuf, Entry>,
+    /// Paths inserted since the cache file was last written
+    pending: Mutex<Vec<PathBuf>>,
+    /// Set if the cache file cannot simply be appended to, either because it
+    /// is missing, unreadable or truncated, or because entries were removed
+    rewrite: AtomicBool,
+    last_flush: Mutex<Instant>,
+}
+
+impl Cache {
+    /// Open the cache stored at `path`, starting with an empty cache if it
+    /// does not exist or cannot be read
+    pub fn load(path: PathBuf) -> Self {
+        let (entries, complete) = match Self::read(&path) {
+            Ok(Some((e, complete))) => {
+                info!("Loaded {} cached hash(es) from {:?}", e.len(), path);
+                (e, complete)
+            },
+            Ok(None) => (DashMap::default(), false),
+            Err(e) => {
+                warn!("Not using existing cache {:?}: {:?}", path, e);
+                (DashMap::default(), false)
+            },
+        };
+
+        Self {
+            path,
+            entries,
+            pending: Mutex::default(),
+            rewrite: AtomicBool::new(!complete),
+            last_flush: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Read the cache file, returning `None` if it does not exist.  The flag
+    /// returned alongside the entries is false if the file ended partway
+    /// through a record.
+    fn read(path: &Path) -> Result<Option<(DashMap<PathBuf, Entry>, bool)>> {
+        let mut file = match File::open(path) {
+            Ok(f) => BufReader::new(f),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context("Failed to open cache file"),
+        };
+
+        let version: u32 =
+            bincode::deserialize_from(&mut file).context("Failed to deserialize cache")?;
+
+        if version != VERSION {
+            bail!("Unsupported cache version {}", version);
+        }
+
+        let entries = DashMap::default();
+
+        while !file.fill_buf().context("Failed to read cache file")?.is_empty() {
+            let (path, entry): (Vec<u8>, Entry) = match bincode::deserialize_from(&mut file) {
+                Ok(r) => r,
+                Err(e) => {
+                    // Most likely an interrupted append, so keep everything
+                    // before it
+                    warn!("Ignoring truncated cache record in {:?}: {:?}", path, e);
+                    return Ok(Some((entries, false)));
+                },
+            };
+
+            entries.insert(OsString::from_vec(path).into(), entry);
+        }
+
+        Ok(Some((entries, true)))
+    }
+
+    /// Look up the hash for a file, returning `None` if it is not cached, its
+    /// metadata no longer matches, or it was computed with a different
+    /// algorithm
+    pub fn get(&self, path: &Path, meta: &Meta, algorithm: Algorithm) -> Option<Hash> {
+        let entry = self.entries.get(path)?;
+
+        if entry.key != Key::new(meta) {
+            trace!("Cached hash for {:?} is stale", path);
+            return None;
+        }
+
+        if entry.algorithm != algorithm {
+            trace!("Cached hash for {:?} uses {}", path, entry.algorithm);
+            return None;
+        }
+
+        Some(entry.hash.clone())
+    }
+
+    pub fn insert(&self, path: PathBuf, meta: &Meta, algorithm: Algorithm, hash: Hash) {
+        self.entries.insert(path.clone(), Entry {
+            key: Key::new(meta),
+            algorithm,
+            hash,
+        });
+        self.pending.lock().unwrap().push(path);
+    }
+
+    /// Drop stale entries and rewrite the cache file once a scan of `roots`
+    /// has finished
+    pub fn finish(&self, roots: &[PathBuf], seen: &DashSet<PathBuf>) -> Result {
+        self.prune(roots, seen);
+        self.flush()
+    }
+
+    /// Forget every file under one of `roots` that was not seen during the
+    /// scan, as it has been deleted, renamed or excluded since it was cached
+    fn prune(&self, roots: &[PathBuf], seen: &DashSet<PathBuf>) {
+        let len = self.entries.len();
+
+        self.entries
+            .retain(|p, _| seen.contains(p) || !roots.iter().any(|r| p.starts_with(r)));
+
+        let removed = len - self.entries.len();
+
+        if removed > 0 {
+            info!("Removing {} stale hash(es) from the cache", removed);
+            self.rewrite.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Save new hashes to disk if enough time has passed since they were last
+    /// saved, appending to the cache file where possible
+    pub fn maybe_flush(&self) {
+        // If this fails, another thread is already flushing
+        let Ok(mut last_flush) = self.last_flush.try_lock() else {
+            return;
+        };
+
+        if last_flush.elapsed() < FLUSH_INTERVAL {
+            return;
+        }
+
+        *last_flush = Instant::now();
+
+        let res = if self.rewrite.load(Ordering::Relaxed) {
+            self.flush()
+        } else {
+            self.append()
+        };
+
+        match res {
+            Ok(()) => (),
+            Err(e) => warn!("Failed to save cache: {:?}", e),
+        }
+    }
+
+    /// Atomically rewrite the cache file if it has changed
+    fn flush(&self) -> Result {
+        let pending = mem::take(&mut *self.pending.lock().unwrap());
+
+        let rewrite = self.rewrite.swap(false, Ordering::Relaxed);
+
+        if pending.is_empty() && !rewrite {
+            return Ok(());
+        }
+
+        let res = self.write();
+
+        if res.is_err() {
+            self.rewrite.store(true, Ordering::Relaxed);
+        }
+
+        res
+    }
+
+    fn append(&self) -> Result {
+        let pending = mem::take(&mut *self.pending.lock().unwrap());
+
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        trace!("Appending {} hash(es) to {:?}", pending.len(), self.path);
+
+        let res = self.write_records(&pending);
+
+        // A failed append may have left a partial record at the end of the
+        // file, so start over from scratch next time
+        if res.is_err() {
+            self.rewrite.store(true, Ordering::Relaxed);
+        }
+
+        res
+    }
+
+    fn write_records(&self, paths: &[PathBuf]) -> Result {
+        let mut file = BufWriter::new(
+            OpenOptions::new()
+                .append(true)
+                .open(&self.path)
+                .context("Failed to open cache file")?,
+        );
+
+        for path in paths {
+            let Some(entry) = self.entries.get(path) else {
+                continue;
+            };
+
+            write_record(&mut file, path, &entry)?;
+        }
+
+        file.into_inner()
+            .map_err(io::IntoInnerError::into_error)
+            .and_then(|f| f.sync_data())
+            .context("Failed to write cache file")
+    }
+
+    fn write(&self) -> Result {
+        let tmp = crate::staging_path(&self.path);
+
+        self.create(&tmp).inspect_err(|_| {
+            if let Err(e) = fs::remove_file(&tmp) {
+                error!("Failed to clean up {:?}: {:?}", tmp, e);
+            }
+        })?;
+
+        fs::rename(&tmp, &self.path).context("Failed to overwrite cache file")
+    }
+
+    fn create(&self, path: &Path) -> Result {
+        let mut file =
+            BufWriter::new(File::create(path).context("Failed to open temporary file")?);
+
+        bincode::serialize_into(&mut file, &VERSION).context("Failed to serialize cache")?;
+
+        for entry in &self.entries {
+            write_record(&mut file, entry.key(), entry.value())?;
+        }
+
+        file.flush()
+            .and_then(|()| file.get_ref().sync_all())
+            .context("Failed to write temporary file")
+    }
+}
+
+/// Write a single cache record, storing the path as raw bytes so that
+/// non-UTF-8 paths survive the round trip
+fn write_record(file: &mut impl Write, path: &Path, entry: &Entry) -> Result {
+    bincode::serialize_into(file, &(path.as_os_str().as_bytes(), entry))
+        .context("Failed to serialize cache")
+}
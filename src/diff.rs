@@ -0,0 +1,156 @@
+//! Comparison of the duplicate groups found by two scans
+
+use std::{
+    collections::BTreeSet,
+    io,
+    io::prelude::*,
+    path::Path,
+};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    digest::Hash,
+    hash::HashMap,
+    report::{Group, Kind},
+    results::Results,
+    Result,
+};
+
+/// How a group differs between two scans
+enum Change<'a> {
+    Added(&'a Group),
+    Removed(&'a Group),
+    Changed {
+        group: &'a Group,
+        added: Vec<&'a Path>,
+        removed: Vec<&'a Path>,
+    },
+}
+
+/// Print the groups that appeared, disappeared or changed members between an
+/// older and a newer scan
+pub fn run(old: &Results, new: &Results) -> Result {
+    if old.header.algorithm != new.header.algorithm {
+        bail!(
+            "Scans used different hash algorithms ({} and {}) and cannot be compared",
+            old.header.algorithm,
+            new.header.algorithm
+        );
+    }
+
+    let (old_groups, new_groups) = (index(old), index(new));
+
+    let mut changes: Vec<_> = new
+        .groups
+        .iter()
+        .filter_map(|g| match old_groups.get(&(g.kind, &g.hash)) {
+            None => Some(Change::Added(g)),
+            Some(o) => {
+                let (before, after) = (paths(o), paths(g));
+
+                (before != after).then(|| Change::Changed {
+                    group: g,
+                    added: after.difference(&before).copied().collect(),
+                    removed: before.difference(&after).copied().collect(),
+                })
+            },
+        })
+        .chain(
+            old.groups
+                .iter()
+                .filter(|g| !new_groups.contains_key(&(g.kind, &g.hash)))
+                .map(Change::Removed),
+        )
+        .collect();
+
+    changes.sort_by(|a, b| {
+        let key = |c: &Change| {
+            let g = match *c {
+                Change::Added(g) | Change::Removed(g) | Change::Changed { group: g, .. } => g,
+            };
+            (std::cmp::Reverse(g.size), g.kind, g.hash.clone())
+        };
+
+        key(a).cmp(&key(b))
+    });
+
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+
+    write_changes(&mut out, &changes)
+        .and_then(|()| out.flush())
+        .context("Failed to write diff")?;
+
+    let count = |f: fn(&Change) -> bool| changes.iter().filter(|c| f(c)).count();
+
+    eprintln!(
+        "{} new, {} resolved and {} changed group(s)",
+        count(|c| matches!(c, Change::Added(_))),
+        count(|c| matches!(c, Change::Removed(_))),
+        count(|c| matches!(c, Change::Changed { .. })),
+    );
+
+    Ok(())
+}
+
+fn write_changes(mut out: impl Write, changes: &[Change]) -> io::Result<()> {
+    for (i, change) in changes.iter().enumerate() {
+        if i != 0 {
+            writeln!(out)?;
+        }
+
+        match change {
+            Change::Added(g) => write_group(&mut out, '+', g)?,
+            Change::Removed(g) => write_group(&mut out, '-', g)?,
+            Change::Changed {
+                group,
+                added,
+                removed,
+            } => {
+                write_header(&mut out, '~', group)?;
+
+                for path in removed {
+                    writeln!(out, "  - {}", path.display())?;
+                }
+
+                for path in added {
+                    writeln!(out, "  + {}", path.display())?;
+                }
+            },
+        }
+    }
+
+    Ok(())
+}
+
+fn write_header(mut out: impl Write, sign: char, group: &Group) -> io::Result<()> {
+    writeln!(
+        out,
+        "{} {}:{} ({} bytes, {} {})",
+        sign,
+        group.algorithm,
+        group.hash,
+        group.size,
+        group.files.len(),
+        group.kind.plural()
+    )
+}
+
+fn write_group(mut out: impl Write, sign: char, group: &Group) -> io::Result<()> {
+    write_header(&mut out, sign, group)?;
+
+    for entry in &group.files {
+        writeln!(out, "    {}", entry.path.display())?;
+    }
+
+    Ok(())
+}
+
+fn index(results: &Results) -> HashMap<(Kind, &Hash), &Group> {
+    results.groups.iter().map(|g| ((g.kind, &g.hash), g)).collect()
+}
+
+fn paths(group: &Group) -> BTreeSet<&Path> {
+    group.files.iter().map(|e| e.path.as_path()).collect()
+}
@@ -0,0 +1,198 @@
+//! Content hashing algorithms
+
+use std::{
+    fmt,
+    fmt::{Display, Formatter},
+    io,
+};
+
+use clap::ArgEnum;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256, Sha512};
+use xxhash_rust::xxh3::Xxh3;
+
+/// A hashing algorithm usable for comparing file contents
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ArgEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Algorithm {
+    Blake3,
+    #[clap(name = "xxh3-128")]
+    #[serde(rename = "xxh3-128")]
+    Xxh3,
+    Sha256,
+    Sha512,
+}
+
+impl Display for Algorithm {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Blake3 => "blake3",
+            Self::Xxh3 => "xxh3-128",
+            Self::Sha256 => "sha256",
+            Self::Sha512 => "sha512",
+        })
+    }
+}
+
+impl Algorithm {
+    pub fn hasher(self) -> Hasher {
+        match self {
+            Self::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+            Self::Xxh3 => Hasher::Xxh3(Box::new(Xxh3::new())),
+            Self::Sha256 => Hasher::Sha256(Sha256::new()),
+            Self::Sha512 => Hasher::Sha512(Sha512::new()),
+        }
+    }
+}
+
+/// An in-progress hash computation for any [`Algorithm`]
+pub enum Hasher {
+    Blake3(Box<blake3::Hasher>),
+    Xxh3(Box<Xxh3>),
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl fmt::Debug for Hasher {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_tuple("Hasher")
+            .field(&match self {
+                Self::Blake3(_) => Algorithm::Blake3,
+                Self::Xxh3(_) => Algorithm::Xxh3,
+                Self::Sha256(_) => Algorithm::Sha256,
+                Self::Sha512(_) => Algorithm::Sha512,
+            })
+            .finish()
+    }
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Blake3(h) => {
+                h.update(data);
+            },
+            Self::Xxh3(h) => h.update(data),
+            Self::Sha256(h) => h.update(data),
+            Self::Sha512(h) => h.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> Hash {
+        Hash(match self {
+            Self::Blake3(h) => h.finalize().as_bytes()[..].into(),
+            Self::Xxh3(h) => h.digest128().to_be_bytes()[..].into(),
+            Self::Sha256(h) => h.finalize()[..].into(),
+            Self::Sha512(h) => h.finalize()[..].into(),
+        })
+    }
+}
+
+impl io::Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+/// The digest produced by a [`Hasher`].  Serialized as a hex string for
+/// human-readable formats and as raw bytes otherwise.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Hash(Box<[u8]>);
+
+impl AsRef<[u8]> for Hash {
+    fn as_ref(&self) -> &[u8] { &self.0 }
+}
+
+impl Display for Hash {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{:02x}", b))
+    }
+}
+
+impl Serialize for Hash {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        if ser.is_human_readable() {
+            ser.collect_str(self)
+        } else {
+            ser.serialize_bytes(&self.0)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Hash {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        if de.is_human_readable() {
+            let s = String::deserialize(de)?;
+
+            if s.len() % 2 != 0 {
+                return Err(de::Error::custom("odd number of hex digits in hash"));
+            }
+
+            (0..s.len())
+                .step_by(2)
+                .map(|i| {
+                    // from_str_radix would also accept a leading sign
+                    s.get(i..i + 2)
+                        .filter(|b| b.bytes().all(|c| c.is_ascii_hexdigit()))
+                        .and_then(|b| u8::from_str_radix(b, 16).ok())
+                        .ok_or_else(|| de::Error::custom("invalid hex digit in hash"))
+                })
+                .collect::<Result<_, _>>()
+                .map(Self)
+        } else {
+            Vec::<u8>::deserialize(de).map(|v| Self(v.into()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(algorithm: Algorithm, data: &[u8]) -> Hash {
+        let mut hasher = algorithm.hasher();
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    #[test]
+    fn hex() {
+        let h = hash(Algorithm::Sha256, b"abc");
+
+        assert_eq!(
+            h.to_string(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(serde_json::to_string(&h).unwrap(), format!("\"{}\"", h));
+    }
+
+    #[test]
+    fn round_trip() {
+        for algorithm in [
+            Algorithm::Blake3,
+            Algorithm::Xxh3,
+            Algorithm::Sha256,
+            Algorithm::Sha512,
+        ] {
+            let h = hash(algorithm, b"latke");
+
+            let json = serde_json::to_string(&h).unwrap();
+            assert_eq!(serde_json::from_str::<Hash>(&json).unwrap(), h);
+
+            let bin = bincode::serialize(&h).unwrap();
+            assert_eq!(bincode::deserialize::<Hash>(&bin).unwrap(), h);
+        }
+    }
+
+    #[test]
+    fn bad_hex() {
+        assert!(serde_json::from_str::<Hash>(r#""abc""#).is_err());
+        assert!(serde_json::from_str::<Hash>(r#""zz""#).is_err());
+        assert!(serde_json::from_str::<Hash>(r#""+f""#).is_err());
+        assert!(serde_json::from_str::<Hash>(r#""é0""#).is_err());
+        assert_eq!(serde_json::from_str::<Hash>(r#""0aFf""#).unwrap().as_ref(), [0x0a, 0xff]);
+    }
+}
@@ -1,6 +1,8 @@
 use std::{
     fs,
+    os::unix::ffi::OsStrExt,
     path::{Path, PathBuf},
+    sync::atomic::Ordering,
 };
 
 use anyhow::{anyhow, Context};
@@ -9,18 +11,54 @@ use topograph::{graph::DependencyBag, prelude::*};
 
 use crate::{
     dev_id::DevId,
+    digest::Hash,
     hash::{HashMap, HashSet},
-    Item, Job, Result, Worker,
+    file, ignores, symlink, Item, Job, Meta, Result, Special, Worker,
 };
 
+/// A set of directories sharing the same digest
+#[derive(Debug)]
+pub struct DirGroup {
+    /// Total size of the files contained in each directory
+    pub size: u64,
+    pub dirs: HashMap<PathBuf, Meta>,
+}
+
+/// The contents of a directory as of when it was walked
+#[derive(Debug)]
+pub struct Listing {
+    pub meta: Meta,
+    pub children: HashSet<Item>,
+    /// Levels below the search path the directory was found at
+    pub depth: usize,
+}
+
 pub fn recurse(
     path: PathBuf,
+    meta: Meta,
     root_id: Option<DevId>,
+    depth: usize,
+    ignores: ignores::Stack,
     handle: crate::Handle,
     worker: impl AsRef<Worker>,
 ) -> Result {
-    let Worker { ref seen, .. } = *worker.as_ref();
+    let Worker {
+        ignore_files,
+        max_depth,
+        ref seen,
+        ref skipped,
+        ..
+    } = *worker.as_ref();
 
+    let ignores = if ignore_files {
+        ignores.enter(&path)
+    } else {
+        ignores
+    };
+
+    // Children past the maximum depth are still listed, so that this
+    // directory is known to be incomplete and is never given a digest
+    let descend = max_depth.is_none_or(|m| depth < m);
     let mut children = Vec::new();
     let mut child_paths = HashSet::default();
 
@@ -29,21 +67,42 @@ pub fn recurse(
     {
         match child.and_then(|c| Ok((c.path(), c.metadata()?))) {
             Ok((path, meta)) => {
+                // Excluded paths are left out of the listing entirely, so
+                // they do not affect the parent's digest
+                if excluded(&path, meta.is_dir(), &ignores, worker.as_ref()) {
+                    skipped.excluded.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+
                 child_paths.insert(Item::new(path.clone(), meta.clone()));
 
+                if !descend {
+                    skipped.too_deep.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+
                 if seen.contains(&path) {
                     continue;
                 }
 
-                if let Some(job) = Job::path(path, meta, root_id, worker.as_ref())? {
+                if let Some(job) =
+                    Job::path(path, meta, root_id, depth + 1, ignores.clone(), worker.as_ref())?
+                {
                     children.push(job);
                 }
             },
-            Err(e) => error!("Error while reading directory {:?}: {:?}", path, e),
+            Err(e) => {
+                let e = e.into();
+                skipped.error(&e);
+                error!("Error while reading directory {:?}: {:?}", path, e);
+            },
         }
     }
 
-    let mut deps = handle.create_node_or_run(Job::FinalizeDir(path, child_paths), children.len());
+    let mut deps = handle.create_node_or_run(
+        Job::FinalizeDir(path, meta, child_paths, depth, ignores),
+        children.len(),
+    );
 
     for job in children {
         handle.push_dependency(job, deps.as_mut().map(DependencyBag::take));
@@ -54,16 +113,14 @@ pub fn recurse(
 
 #[allow(clippy::unnecessary_wraps)]
 pub fn finalize(
-    path: impl AsRef<Path>,
+    path: PathBuf,
+    meta: Meta,
     children: HashSet<Item>,
+    depth: usize,
+    ignores: &ignores::Stack,
     worker: impl AsRef<Worker>,
 ) -> Result {
-    let Worker {
-        ref hash_for_path,
-        ref file_hashes,
-        ..
-    } = *worker.as_ref();
-    let path = path.as_ref();
+    let Worker { ref listings, .. } = *worker.as_ref();
 
     match fs::read_dir(&path)
         .with_context(|| format!("Failed to open directory {:?}", path))
@@ -72,6 +129,11 @@ pub fn finalize(
                 c.and_then(|c| c.metadata().map(|m| Item::new(c.path(), m)))
                     .with_context(|| format!("Error while reading directory {:?}", path))
             })
+            .filter(|i| {
+                i.as_ref().map_or(true, |i| {
+                    !excluded(i.path(), matches!(i, Item::Dir(..)), ignores, worker.as_ref())
+                })
+            })
             .collect()
         })
         .and_then(|c: HashSet<_>| {
@@ -88,33 +150,193 @@ pub fn finalize(
         ),
     }
 
-    let child_count = children.len();
-    let mut child_infos = HashMap::default();
+    info!("{:?}: {} child(ren)", path, children.len());
+
+    listings.insert(path, Listing {
+        meta,
+        children,
+        depth,
+    });
+
+    Ok(())
+}
+
+/// Whether a path found in a directory should be left out of the search,
+/// either by a filter given on the command line or by an ignore file
+pub fn excluded(path: &Path, is_dir: bool, ignores: &ignores::Stack, worker: &Worker) -> bool {
+    !worker.filter.allows(path, is_dir) || ignores.is_ignored(path, is_dir)
+}
+
+/// Compute a digest for every walked directory from the names and hashes of
+/// its children, once all files have been hashed.  A directory containing any
+/// child without a hash (for instance, a file with a unique size) cannot have
+/// a duplicate, and so is given no digest.
+pub fn digest_all(worker: impl AsRef<Worker>) {
+    let worker = worker.as_ref();
+    let paths: Vec<_> = worker.listings.iter().map(|l| l.key().clone()).collect();
+    let mut visited = HashSet::default();
+
+    for path in paths {
+        visit(&path, &mut visited, worker);
+    }
+}
+
+/// Digest a directory after every directory its digest depends on, which
+/// besides its subdirectories includes the targets of any followed symlinks
+/// inside it.  Those may be anywhere, so their digests cannot be ordered by
+/// depth alone.  A directory reached again while its own dependencies are
+/// still being visited is part of a link cycle, and so is left without a
+/// digest along with everything containing it.
+fn visit(path: &Path, visited: &mut HashSet<PathBuf>, worker: &Worker) {
+    let Worker {
+        symlinks,
+        min_depth,
+        ref listings,
+        ref link_targets,
+        ..
+    } = *worker;
+
+    if !visited.insert(path.to_owned()) {
+        return;
+    }
+
+    let Some(listing) = listings.get(path) else {
+        return;
+    };
+
+    for child in &listing.children {
+        match child {
+            Item::Dir(p, _) => visit(p, visited, worker),
+            Item::Symlink(p, _) if symlinks == symlink::Policy::Follow => {
+                if let Some(target) = link_targets.get(p) {
+                    visit(target.value(), visited, worker);
+                }
+            },
+            Item::File(..) | Item::Symlink(..) | Item::Special(..) => (),
+        }
+    }
+
+    // Directories above the minimum depth are never compared, which also
+    // leaves their parents without a digest
+    if listing.depth < min_depth {
+        return;
+    }
+
+    if let Some((hash, size)) = digest(&listing.children, worker) {
+        record(path.to_owned(), listing.meta.clone(), hash, size, worker);
+    }
+}
+
+fn digest(children: &HashSet<Item>, worker: &Worker) -> Option<(Hash, u64)> {
+    let Worker {
+        algorithm,
+        symlinks,
+        ref hash_for_dir,
+        ref dir_hashes,
+        ref link_targets,
+        ref hash_for_link,
+        ..
+    } = *worker;
+
+    let mut children: Vec<_> = children.iter().collect();
+    children.sort_by(|a, b| a.path().file_name().cmp(&b.path().file_name()));
+
+    let mut hasher = algorithm.hasher();
+    let mut size = 0;
 
     for child in children {
-        let info = match &child {
-            Item::File(path, _) => {
-                let hash = hash_for_path.get(path).unwrap().value();
+        let (tag, hash, len) = match child {
+            Item::File(p, m) => {
+                let (tag, hash) = file_value(p, m, worker)?;
+
+                (tag, hash, m.len())
+            },
+            Item::Dir(p, _) => {
+                let hash = hash_for_dir.get(p)?.clone();
+                let len = dir_hashes.get(&hash)?.size;
 
-                let mut info = file_hashes.get(hash).unwrap().value().clone();
-                assert!(info.remove(path).is_some());
+                (b'd', Some(hash), len)
+            },
+            Item::Symlink(p, _) => match symlinks {
+                symlink::Policy::Skip => continue,
+                symlink::Policy::Follow => {
+                    let target = link_targets.get(p)?;
+
+                    if let Some(hash) = hash_for_dir.get(target.value()) {
+                        let len = dir_hashes.get(hash.value())?.size;
+
+                        (b'd', Some(hash.clone()), len)
+                    } else {
+                        // Only the path of a link's target is kept
+                        let meta = fs::metadata(target.value()).ok()?;
+                        let (tag, hash) = file_value(target.value(), &meta, worker)?;
+
+                        (tag, hash, meta.len())
+                    }
+                },
+                // Links contain no data, so they do not count toward the size
+                symlink::Policy::HashTargetPath => (b'l', Some(hash_for_link.get(p)?.clone()), 0),
+            },
+            // Special files are compared by type alone
+            Item::Special(_, _, s) => {
+                let tag = match s {
+                    Special::Fifo => b'p',
+                    Special::Socket => b's',
+                    Special::BlockDevice => b'b',
+                    Special::CharDevice => b'c',
+                };
 
-                info
+                (tag, None, 0)
             },
-            Item::Dir(_path, _) => todo!("Handle dir"),
-            Item::Symlink(_path, _) => todo!("Handle symlink"),
         };
 
-        assert!(child_infos.insert(child, info).is_none());
+        hasher.update(child.path().file_name()?.as_bytes());
+        hasher.update(&[0, tag]);
+
+        if let Some(hash) = hash {
+            hasher.update(hash.as_ref());
+        }
+
+        size += len;
     }
 
-    info!(
-        "{:?}: {}/{} - {:?}",
-        path,
-        child_infos.len(),
-        child_count,
-        child_infos
-    );
+    Some((hasher.finalize(), size))
+}
 
-    Ok(())
+/// The tag and hash identifying a file in its directory's digest.  Empty files
+/// are never hashed, since their contents are known, and hardlinks to a file
+/// that was never read are identified by their inode instead, since they can
+/// only be identical to one another.
+fn file_value(path: &Path, meta: &Meta, worker: &Worker) -> Option<(u8, Option<Hash>)> {
+    if meta.len() == 0 {
+        Some((b'e', None))
+    } else if let Some(hash) = worker.hash_for_path.get(path) {
+        Some((b'f', Some(hash.clone())))
+    } else if file::hardlinked(meta, worker) {
+        Some((b'i', Some(file::inode_hash(worker.algorithm, meta))))
+    } else {
+        None
+    }
+}
+
+fn record(path: PathBuf, meta: Meta, hash: Hash, size: u64, worker: &Worker) {
+    let Worker {
+        ref hash_for_dir,
+        ref dir_hashes,
+        ..
+    } = *worker;
+
+    if hash_for_dir.insert(path.clone(), hash.clone()).is_none() {
+        assert!(
+            dir_hashes
+                .entry(hash)
+                .or_insert_with(|| DirGroup {
+                    size,
+                    dirs: HashMap::default(),
+                })
+                .dirs
+                .insert(path, meta)
+                .is_none()
+        );
+    }
 }
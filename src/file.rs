@@ -1,41 +1,283 @@
-use std::{fs::File, io, io::BufReader, path::PathBuf};
+use std::{
+    fs::File,
+    io,
+    io::{prelude::*, BufReader, SeekFrom},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use anyhow::Context;
-use sha2::{Digest, Sha512};
+use log::info;
 
-use crate::{hash::HashMap, Meta, Result, Worker};
+use crate::{
+    digest::{Algorithm, Hash},
+    hash::HashMap,
+    Job, Meta, Result, Worker,
+};
 
-pub type Hash = [u8; 64];
+/// Algorithm used to compare samples of files.  Since this is only used to rule
+/// out files that cannot be identical, it does not need to be cryptographic.
+const SAMPLE_ALGORITHM: Algorithm = Algorithm::Xxh3;
+
+/// Forwards data to a hasher, counting it as it goes (if a counter is given)
+/// so that progress can be displayed while large files are read
+struct Counted<'a, W> {
+    inner: W,
+    count: Option<&'a AtomicU64>,
+}
+
+impl<W: Write> Write for Counted<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+
+        if let Some(count) = self.count {
+            count.fetch_add(n as u64, Ordering::Relaxed);
+        }
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> { self.inner.flush() }
+}
+
+/// Identifies the inode backing a file
+fn inode(meta: &Meta) -> (u64, u64) { (meta.dev(), meta.ino()) }
+
+/// Returns true if more than one path to this file's inode has been found
+pub fn hardlinked(meta: &Meta, worker: &Worker) -> bool {
+    worker.inodes.get(&inode(meta)).is_some_and(|l| l.len() > 1)
+}
+
+/// A value identifying a file's inode rather than its contents, for hardlinks
+/// to a file that was never read because nothing else shares its size or
+/// sample.  Such a file can only be identical to its own links.
+pub fn inode_hash(algorithm: Algorithm, meta: &Meta) -> Hash {
+    let (dev, ino) = inode(meta);
+    let mut hasher = algorithm.hasher();
+
+    hasher.update(b"inode\0");
+    hasher.update(&dev.to_le_bytes());
+    hasher.update(&ino.to_le_bytes());
+
+    hasher.finalize()
+}
+
+/// Record a file by its size, to be hashed later if another file of the same
+/// size is found.  Only the first path found to each inode is recorded, so
+/// hardlinked files are only read once.
+pub fn bucket(path: PathBuf, meta: Meta, worker: impl AsRef<Worker>) {
+    let Worker {
+        ref inodes,
+        ref size_buckets,
+        ref empty_files,
+        ..
+    } = *worker.as_ref();
+
+    let first = {
+        let mut links = inodes.entry(inode(&meta)).or_insert_with(Vec::new);
+        links.push((path.clone(), meta.clone()));
+        links.len() == 1
+    };
+
+    // Every empty file is trivially identical to every other, so they are
+    // kept apart and never opened or hashed
+    if meta.len() == 0 {
+        empty_files.insert(path, meta);
+    } else if first {
+        size_buckets
+            .entry(meta.len())
+            .or_insert_with(Vec::new)
+            .push((path, meta));
+    }
+}
+
+/// Produce a job for every file sharing its size with another file.  Files
+/// with a valid cached hash are recorded immediately without being read at
+/// all.  Files small enough that sampling
+/// them would read most of their contents anyway are hashed in full
+/// immediately; the rest are sampled first.
+pub fn sample_jobs(worker: impl AsRef<Worker>) -> Vec<Job> {
+    let worker = worker.as_ref();
+    let Worker {
+        sample_size,
+        algorithm,
+        ref cache,
+        ref bytes_queued,
+        ref size_buckets,
+        ..
+    } = *worker;
+
+    let jobs: Vec<_> = size_buckets
+        .iter()
+        .filter(|b| b.value().len() > 1)
+        .flat_map(|b| {
+            let mut any_cached = false;
+
+            let files: Vec<_> = b
+                .value()
+                .iter()
+                .filter(|(p, m)| match cache.as_ref().and_then(|c| c.get(p, m, algorithm)) {
+                    Some(hash) => {
+                        record(p.clone(), m.clone(), &hash, worker);
+                        any_cached = true;
+                        false
+                    },
+                    None => true,
+                })
+                .collect();
+
+            // Cached files have no sample to compare against, so any other
+            // file of the same size cannot be ruled out by sampling it
+            let sample = !any_cached && sample_size != 0 && *b.key() > 2 * sample_size;
+
+            files
+                .into_iter()
+                .map(|(p, m)| {
+                    if sample {
+                        bytes_queued.fetch_add(2 * sample_size, Ordering::Relaxed);
+                        Job::SampleFile(p.clone(), m.clone())
+                    } else {
+                        bytes_queued.fetch_add(m.len(), Ordering::Relaxed);
+                        Job::HashFile(p.clone(), m.clone())
+                    }
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect();
+
+    info!("{} file(s) share a size with another file", jobs.len());
+
+    jobs
+}
+
+/// Produce a hashing job for every sampled file whose sample matches that of
+/// another file
+pub fn hash_jobs(worker: impl AsRef<Worker>) -> Vec<Job> {
+    let worker = worker.as_ref();
+    let Worker {
+        ref bytes_queued,
+        ref sample_buckets,
+        ..
+    } = *worker;
+
+    let jobs: Vec<_> = sample_buckets
+        .iter()
+        .filter(|b| b.value().len() > 1)
+        .flat_map(|b| {
+            b.value()
+                .iter()
+                .map(|(p, m)| {
+                    bytes_queued.fetch_add(m.len(), Ordering::Relaxed);
+                    Job::HashFile(p.clone(), m.clone())
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect();
+
+    info!("{} sampled file(s) share a sample with another file", jobs.len());
+
+    jobs
+}
+
+/// Hash the first and last `sample_size` bytes of a file, recording it by its
+/// size and partial hash
+pub fn sample(path: PathBuf, meta: Meta, worker: impl AsRef<Worker>) -> Result {
+    let Worker {
+        sample_size,
+        ref bytes_hashed,
+        ref sample_buckets,
+        ..
+    } = *worker.as_ref();
+
+    let mut file = File::open(&path).with_context(|| format!("Failed to open file {:?}", path))?;
+    let mut buf = vec![0_u8; usize::try_from(sample_size).context("Sample size too large")?];
+    let mut hasher = SAMPLE_ALGORITHM.hasher();
+
+    file.read_exact(&mut buf)
+        .and_then(|()| {
+            hasher.update(&buf);
+            file.seek(SeekFrom::End(-i64::try_from(sample_size).unwrap()))
+        })
+        .and_then(|_| file.read_exact(&mut buf))
+        .with_context(|| format!("Failed to sample {:?}", path))?;
+    hasher.update(&buf);
+    bytes_hashed.fetch_add(2 * sample_size, Ordering::Relaxed);
+
+    sample_buckets
+        .entry((meta.len(), hasher.finalize()))
+        .or_insert_with(Vec::new)
+        .push((path, meta));
+
+    Ok(())
+}
 
 pub fn hash(path: PathBuf, meta: Meta, worker: impl AsRef<Worker>) -> Result {
+    let worker = worker.as_ref();
     let Worker {
         block_size,
-        ref hash_for_path,
-        ref file_hashes,
+        algorithm,
+        ref cache,
+        ref bytes_hashed,
         ..
-    } = *worker.as_ref();
+    } = *worker;
+
+    let hash = hash_contents(&path, algorithm, block_size, Some(bytes_hashed))?;
 
+    if let Some(ref cache) = **cache {
+        cache.insert(path.clone(), &meta, algorithm, hash.clone());
+        cache.maybe_flush();
+    }
+
+    record(path, meta, &hash, worker);
+
+    Ok(())
+}
+
+/// Hash the full contents of a file, adding the number of bytes read to
+/// `count` as it goes
+pub fn hash_contents(
+    path: &Path,
+    algorithm: Algorithm,
+    block_size: usize,
+    count: Option<&AtomicU64>,
+) -> Result<Hash> {
     let mut file = BufReader::with_capacity(
         block_size,
-        File::open(&path).with_context(|| format!("Failed to open file {:?}", path))?,
+        File::open(path).with_context(|| format!("Failed to open file {:?}", path))?,
     );
 
-    let mut hasher = Sha512::new();
+    let mut hasher = Counted {
+        inner: algorithm.hasher(),
+        count,
+    };
     io::copy(&mut file, &mut hasher).with_context(|| format!("Failed to hash {:?}", path))?;
-    let hash = hasher.finalize();
-
-    let mut bytes = [0u8; 64];
-    bytes[..].copy_from_slice(hash.as_slice());
-
-    if hash_for_path.insert(path.clone(), bytes).is_none() {
-        assert!(
-            file_hashes
-                .entry(bytes)
-                .or_insert_with(HashMap::default)
-                .insert(path, meta)
-                .is_none()
-        );
-    }
 
-    Ok(())
+    Ok(hasher.inner.finalize())
+}
+
+/// Record the hash of a file under every path that links to its inode
+fn record(path: PathBuf, meta: Meta, hash: &Hash, worker: &Worker) {
+    let Worker {
+        ref inodes,
+        ref hash_for_path,
+        ref file_hashes,
+        ..
+    } = *worker;
+
+    let links = inodes
+        .get(&inode(&meta))
+        .map_or_else(|| vec![(path, meta)], |l| l.value().clone());
+
+    for (path, meta) in links {
+        if hash_for_path.insert(path.clone(), hash.clone()).is_none() {
+            assert!(
+                file_hashes
+                    .entry(hash.clone())
+                    .or_insert_with(HashMap::default)
+                    .insert(path, meta)
+                    .is_none()
+            );
+        }
+    }
 }
@@ -0,0 +1,158 @@
+//! Include and exclude rules deciding which paths the walk visits
+
+use std::{os::unix::ffi::OsStrExt, path::Path};
+
+use anyhow::Context;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use regex::bytes::RegexSet;
+
+use crate::Result;
+
+/// A set of glob patterns.  Patterns without a slash are matched against file
+/// names, and patterns with one against the end of the full path.
+#[derive(Debug)]
+struct Patterns {
+    names: GlobSet,
+    paths: GlobSet,
+}
+
+impl Patterns {
+    fn new(patterns: &[String]) -> Result<Self> {
+        let mut names = GlobSetBuilder::new();
+        let mut paths = GlobSetBuilder::new();
+
+        for pattern in patterns {
+            let (set, glob) = if pattern.contains('/') {
+                let pattern = pattern.trim_end_matches('/');
+                let glob = if pattern.starts_with('/') || pattern.starts_with("**") {
+                    pattern.into()
+                } else {
+                    format!("**/{}", pattern)
+                };
+
+                (&mut paths, glob)
+            } else {
+                (&mut names, pattern.clone())
+            };
+
+            set.add(
+                GlobBuilder::new(&glob)
+                    .literal_separator(true)
+                    .build()
+                    .with_context(|| format!("Invalid glob {:?}", pattern))?,
+            );
+        }
+
+        Ok(Self {
+            names: names.build().context("Failed to compile globs")?,
+            paths: paths.build().context("Failed to compile globs")?,
+        })
+    }
+
+    fn is_empty(&self) -> bool { self.names.is_empty() && self.paths.is_empty() }
+
+    fn is_match(&self, path: &Path) -> bool {
+        path.file_name().is_some_and(|n| self.names.is_match(n)) || self.paths.is_match(path)
+    }
+}
+
+/// Decides whether each path found in a directory should be searched
+#[derive(Debug)]
+pub struct Filter {
+    include: Patterns,
+    exclude: Patterns,
+    exclude_regex: RegexSet,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+}
+
+impl Filter {
+    pub fn new(include: &[String], exclude: &[String], exclude_regex: &[String]) -> Result<Self> {
+        Ok(Self {
+            include: Patterns::new(include).context("Invalid --include pattern")?,
+            exclude: Patterns::new(exclude).context("Invalid --exclude pattern")?,
+            exclude_regex: RegexSet::new(exclude_regex).context("Invalid --exclude-regex")?,
+            min_size: None,
+            max_size: None,
+        })
+    }
+
+    /// Only allow files whose size lies within the given inclusive bounds
+    pub fn with_size_range(self, min_size: Option<u64>, max_size: Option<u64>) -> Self {
+        Self {
+            min_size,
+            max_size,
+            ..self
+        }
+    }
+
+    /// Whether a path should be searched.  An excluded directory is skipped
+    /// along with everything inside it, while include patterns only restrict
+    /// which non-directories are searched, so that a pattern like `*.jpg`
+    /// still finds files in subdirectories.
+    pub fn allows(&self, path: &Path, is_dir: bool) -> bool {
+        if self.exclude.is_match(path) || self.exclude_regex.is_match(path.as_os_str().as_bytes())
+        {
+            return false;
+        }
+
+        is_dir || self.include.is_empty() || self.include.is_match(path)
+    }
+
+    /// Whether a file of the given size should be searched
+    pub fn allows_size(&self, len: u64) -> bool {
+        self.min_size.is_none_or(|m| len >= m) && self.max_size.is_none_or(|m| len <= m)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(patterns: &[&str]) -> Patterns {
+        Patterns::new(&patterns.iter().map(|p| (*p).to_owned()).collect::<Vec<_>>()).unwrap()
+    }
+
+    #[test]
+    fn without_slash_matches_names() {
+        let p = patterns(&["*.jpg", "target"]);
+
+        assert!(p.is_match(Path::new("a.jpg")));
+        assert!(p.is_match(Path::new("photos/2021/a.jpg")));
+        assert!(p.is_match(Path::new("src/target")));
+        assert!(!p.is_match(Path::new("a.jpg/b")));
+        assert!(!p.is_match(Path::new("target/b")));
+        assert!(!p.is_match(Path::new("src/target2")));
+    }
+
+    #[test]
+    fn with_slash_matches_path_ends() {
+        let p = patterns(&["src/*.rs", "build/"]);
+
+        assert!(p.is_match(Path::new("src/main.rs")));
+        assert!(p.is_match(Path::new("crate/src/main.rs")));
+        assert!(p.is_match(Path::new("./build")));
+        assert!(!p.is_match(Path::new("crate/src/bin/main.rs")));
+        assert!(!p.is_match(Path::new("mysrc/main.rs")));
+        assert!(!p.is_match(Path::new("main.rs")));
+        assert!(!p.is_match(Path::new("build/out")));
+    }
+
+    #[test]
+    fn anchored_patterns() {
+        let p = patterns(&["/tmp/*", "**/cache/**"]);
+
+        assert!(p.is_match(Path::new("/tmp/a")));
+        assert!(!p.is_match(Path::new("/home/tmp/a")));
+        assert!(!p.is_match(Path::new("/tmp/a/b")));
+        assert!(p.is_match(Path::new("home/.cache/x/cache/y")));
+        assert!(!p.is_match(Path::new("home/.cache/y")));
+    }
+
+    #[test]
+    fn empty() {
+        assert!(patterns(&[]).is_empty());
+        assert!(!patterns(&["a"]).is_empty());
+        assert!(!patterns(&["a/b"]).is_empty());
+    }
+}
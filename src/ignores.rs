@@ -0,0 +1,231 @@
+//! Hierarchical ignore files, read while walking in the same way as ripgrep
+
+use std::{
+    borrow::Cow,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
+use log::warn;
+
+/// Ignore files read in each directory, in increasing order of precedence.
+/// `.gitignore` files are only used inside a Git repository.
+const FILES: [&str; 3] = [".gitignore", ".ignore", ".latkeignore"];
+
+/// The rules read from the ignore files in a single directory
+#[derive(Debug)]
+struct Frame {
+    /// Matchers in decreasing order of precedence
+    matchers: Vec<Gitignore>,
+    parent: Stack,
+}
+
+/// The ignore rules in effect in a directory, made up of those read in it and
+/// in each of its ancestors.  Cloning a stack is cheap, so each job carries the
+/// stack of the directory it was found in.
+#[derive(Debug, Clone, Default)]
+pub struct Stack {
+    frame: Option<Arc<Frame>>,
+    in_git: bool,
+    /// The search path as given and its canonical path.  Rules are always
+    /// read and matched using canonical paths, so that rules from above the
+    /// search path apply to the paths found below it.
+    root: Option<Arc<(PathBuf, PathBuf)>>,
+}
+
+impl Stack {
+    /// Build the stack for a search path from the ignore files in the
+    /// directories above it
+    pub fn for_root(root: &Path) -> Self {
+        let canonical = match fs::canonicalize(root) {
+            Ok(p) => p,
+            Err(e) => {
+                warn!("Failed to resolve {:?}, ignoring its parents' ignore files: {}", root, e);
+                return Self::default();
+            },
+        };
+
+        let mut parents: Vec<_> = canonical.ancestors().skip(1).collect();
+        parents.reverse();
+
+        let stack = parents.into_iter().fold(Self::default(), |s, p| s.enter(p));
+
+        Self {
+            root: Some(Arc::new((root.to_owned(), canonical))),
+            ..stack
+        }
+    }
+
+    /// Translate a path found while walking into the canonical form used to
+    /// read and match rules
+    fn resolve<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+        let Some((ref given, ref canonical)) = self.root.as_deref() else {
+            return path.into();
+        };
+
+        match path.strip_prefix(given) {
+            Ok(rest) if rest.as_os_str().is_empty() => canonical.clone().into(),
+            Ok(rest) => canonical.join(rest).into(),
+            Err(_) => path.into(),
+        }
+    }
+
+    /// Read the ignore files in `dir`, returning the stack for its contents
+    pub fn enter(&self, dir: &Path) -> Self {
+        let dir = &*self.resolve(dir);
+        let in_git = self.in_git || dir.join(".git").exists();
+
+        let matchers: Vec<_> = FILES
+            .iter()
+            .rev()
+            .filter(|n| in_git || **n != ".gitignore")
+            .filter_map(|n| read(dir, &dir.join(n)))
+            .collect();
+
+        if matchers.is_empty() {
+            return Self {
+                frame: self.frame.clone(),
+                in_git,
+                root: self.root.clone(),
+            };
+        }
+
+        Self {
+            frame: Some(Arc::new(Frame {
+                matchers,
+                parent: self.clone(),
+            })),
+            in_git,
+            root: self.root.clone(),
+        }
+    }
+
+    /// Whether a path inside this stack's directory is ignored.  Rules from
+    /// deeper directories take precedence over those from their ancestors.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        if self.frame.is_none() {
+            return false;
+        }
+
+        let path = &*self.resolve(path);
+        let mut stack = self;
+
+        while let Some(ref frame) = stack.frame {
+            for matcher in &frame.matchers {
+                match matcher.matched(path, is_dir) {
+                    Match::None => (),
+                    Match::Ignore(_) => return true,
+                    Match::Whitelist(_) => return false,
+                }
+            }
+
+            stack = &frame.parent;
+        }
+
+        false
+    }
+}
+
+fn read(dir: &Path, file: &Path) -> Option<Gitignore> {
+    if !file.is_file() {
+        return None;
+    }
+
+    let mut builder = GitignoreBuilder::new(dir);
+
+    // Errors may be partial, in which case the valid lines are still used
+    if let Some(e) = builder.add(file) {
+        warn!("Error reading ignore file {:?}: {}", file, e);
+    }
+
+    match builder.build() {
+        Ok(g) if g.is_empty() => None,
+        Ok(g) => Some(g),
+        Err(e) => {
+            warn!("Failed to load ignore file {:?}: {}", file, e);
+            None
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create a fresh directory tree containing the given files
+    fn tree(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("latke-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&root);
+
+        for (path, contents) in files {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+
+        root
+    }
+
+    #[test]
+    fn precedence() {
+        let root = tree("precedence", &[
+            (".git/HEAD", ""),
+            (".gitignore", "*.log\nbuild/\n"),
+            (".ignore", "!keep.log\n*.tmp\n"),
+            (".latkeignore", "!keep.tmp\n"),
+            ("sub/.gitignore", "!sub.log\n"),
+            ("sub/.latkeignore", "*.txt\n"),
+        ]);
+        let top = Stack::for_root(&root).enter(&root);
+        let sub = top.enter(&root.join("sub"));
+
+        assert!(top.is_ignored(&root.join("a.log"), false));
+        assert!(!top.is_ignored(&root.join("keep.log"), false));
+        assert!(top.is_ignored(&root.join("a.tmp"), false));
+        assert!(!top.is_ignored(&root.join("keep.tmp"), false));
+        assert!(top.is_ignored(&root.join("build"), true));
+        assert!(!top.is_ignored(&root.join("build"), false));
+        assert!(!top.is_ignored(&root.join("a.txt"), false));
+
+        assert!(sub.is_ignored(&root.join("sub/a.log"), false));
+        assert!(!sub.is_ignored(&root.join("sub/sub.log"), false));
+        assert!(!sub.is_ignored(&root.join("sub/keep.log"), false));
+        assert!(sub.is_ignored(&root.join("sub/a.txt"), false));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn gitignore_outside_git() {
+        let root = tree("no-git", &[(".gitignore", "*.log\n"), (".ignore", "*.tmp\n")]);
+        let stack = Stack::for_root(&root).enter(&root);
+
+        assert!(!stack.is_ignored(&root.join("a.log"), false));
+        assert!(stack.is_ignored(&root.join("a.tmp"), false));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn parents_of_root() {
+        let root = tree("parents", &[(".ignore", "*.tmp\n"), ("sub/a", "")]);
+        let alias = root.join("alias");
+        std::os::unix::fs::symlink(root.join("sub"), &alias).unwrap();
+
+        // Rules from above the search path apply whether it is given through
+        // a symlink or not
+        for dir in [root.join("sub"), alias] {
+            let stack = Stack::for_root(&dir).enter(&dir);
+
+            assert!(stack.is_ignored(&dir.join("a.tmp"), false));
+            assert!(!stack.is_ignored(&dir.join("a"), false));
+        }
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1,9 +1,27 @@
 #![warn(clippy::pedantic, clippy::cargo)]
+// Paths are deliberately printed with Debug to keep them quoted and escaped
+#![allow(clippy::uninlined_format_args, clippy::unnecessary_debug_formatting)]
+// Not fixable without control over the dependency tree
+#![allow(clippy::multiple_crate_versions)]
 
+mod action;
+mod cache;
 mod dev_id;
+mod digest;
+mod diff;
 mod dir;
 mod file;
+mod filter;
 mod hash;
+mod ignores;
+mod progress;
+mod report;
+mod results;
+mod review;
+mod sqlite;
+mod summary;
+mod symlink;
+mod verify;
 
 use std::{
     cmp,
@@ -14,19 +32,22 @@ use std::{
     fs,
     fs::Metadata,
     hash::{Hash, Hasher},
+    os::unix::fs::FileTypeExt,
     panic::AssertUnwindSafe,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
     },
+    time::Instant,
 };
 
-use anyhow::{bail, Context};
-use clap::Parser;
+use anyhow::{anyhow, bail, Context};
+use clap::{Args, Parser, Subcommand};
 use dev_id::DevId;
 use hash::{DashMap, DashSet, HashMap, HashSet};
 use log::{error, trace, warn};
+use serde::{Deserialize, Serialize};
 use topograph::{graph, prelude::*, threaded};
 
 type Result<T = (), E = anyhow::Error> = std::result::Result<T, E>;
@@ -34,11 +55,36 @@ type Result<T = (), E = anyhow::Error> = std::result::Result<T, E>;
 // May change later
 type Meta = Metadata;
 
+/// A list of files that may be identical to one another
+type Bucket = Vec<(PathBuf, Meta)>;
+
+/// The type of a file that has no meaningful contents to hash
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Special {
+    Fifo,
+    Socket,
+    BlockDevice,
+    CharDevice,
+}
+
+impl Display for Special {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Fifo => "FIFO",
+            Self::Socket => "Socket",
+            Self::BlockDevice => "Block device",
+            Self::CharDevice => "Character device",
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Item {
     File(PathBuf, Meta),
     Dir(PathBuf, Meta),
     Symlink(PathBuf, Meta),
+    Special(PathBuf, Meta, Special),
 }
 
 impl Display for Item {
@@ -47,6 +93,7 @@ impl Display for Item {
             Self::File(p, _) => write!(f, "File {:?}", p),
             Self::Dir(p, _) => write!(f, "Directory {:?}", p),
             Self::Symlink(p, _) => write!(f, "Symlink {:?}", p),
+            Self::Special(p, _, s) => write!(f, "{} {:?}", s, p),
         }
     }
 }
@@ -81,58 +128,113 @@ impl Item {
         } else if meta.is_file() {
             Self::File(path, meta)
         } else {
-            unreachable!();
+            let ty = meta.file_type();
+            let special = if ty.is_fifo() {
+                Special::Fifo
+            } else if ty.is_socket() {
+                Special::Socket
+            } else if ty.is_block_device() {
+                Special::BlockDevice
+            } else if ty.is_char_device() {
+                Special::CharDevice
+            } else {
+                unreachable!();
+            };
+
+            Self::Special(path, meta, special)
         }
     }
 
     fn path(&self) -> &PathBuf {
         match self {
-            Self::File(p, _) | Self::Dir(p, _) | Self::Symlink(p, _) => p,
+            Self::File(p, _) | Self::Dir(p, _) | Self::Symlink(p, _) | Self::Special(p, ..) => p,
         }
     }
 }
 
 #[derive(Debug)]
 pub enum Job {
-    Item(Item, Option<DevId>),
-    FinalizeDir(PathBuf, HashSet<Item>),
+    /// An item found while walking, with the device of its search path, its
+    /// depth below the search path and the ignore rules in effect around it
+    Item(Item, Option<DevId>, usize, ignores::Stack),
+    FinalizeDir(PathBuf, Meta, HashSet<Item>, usize, ignores::Stack),
+    SampleFile(PathBuf, Meta),
+    HashFile(PathBuf, Meta),
 }
 
 impl Display for Job {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match self {
-            Self::Item(i, _) => write!(f, "{}", i),
-            Self::FinalizeDir(p, c) => write!(f, "Finalize dir ({}) {:?}", c.len(), p),
+            Self::Item(i, ..) => write!(f, "{}", i),
+            Self::FinalizeDir(p, _, c, ..) => write!(f, "Finalize dir ({}) {:?}", c.len(), p),
+            Self::SampleFile(p, _) => write!(f, "Sample file {:?}", p),
+            Self::HashFile(p, _) => write!(f, "Hash file {:?}", p),
         }
     }
 }
 
 impl Job {
+    /// The path of the file or directory this job operates on
+    fn item_path(&self) -> &PathBuf {
+        match self {
+            Self::Item(i, ..) => i.path(),
+            Self::FinalizeDir(p, ..) | Self::SampleFile(p, _) | Self::HashFile(p, _) => p,
+        }
+    }
+
     fn path(
         path: PathBuf,
         meta: Metadata,
         root_id: Option<DevId>,
+        depth: usize,
+        ignores: ignores::Stack,
         worker: &Worker,
     ) -> Result<Option<Self>> {
-        let path_id =
-            DevId::new(&path).with_context(|| format!("Failed to get device ID for {:?}", path))?;
+        // Directories above the minimum depth are still searched, since the
+        // items below them may be deep enough.  Followed symlinks may lead to
+        // directories, so this is checked again with their target's metadata.
+        let follow = meta.is_symlink() && worker.symlinks == symlink::Policy::Follow;
 
-        if root_id.map_or(false, |r| r != path_id) {
+        if depth < worker.min_depth && !meta.is_dir() && !follow {
+            worker.skipped.too_shallow.fetch_add(1, Ordering::Relaxed);
             return Ok(None);
         }
 
+        // A symlink always lives on the same device as its parent directory,
+        // and its target may not exist
+        if !meta.is_symlink() {
+            let path_id = DevId::new(&path)
+                .with_context(|| format!("Failed to get device ID for {:?}", path))?;
+
+            if root_id.is_some_and(|r| r != path_id) {
+                worker.skipped.cross_device.fetch_add(1, Ordering::Relaxed);
+                return Ok(None);
+            }
+        }
+
         let item = Item::new(path, meta);
 
         match item {
-            Item::File(..) | Item::Symlink(..) => {
+            Item::File(_, ref meta) if !worker.filter.allows_size(meta.len()) => {
+                worker.skipped.excluded.fetch_add(1, Ordering::Relaxed);
+                return Ok(None);
+            },
+            Item::File(_, ref meta) => {
+                worker.total_files.fetch_add(1, Ordering::Relaxed);
+                worker.total_bytes.fetch_add(meta.len(), Ordering::Relaxed);
+            },
+            Item::Symlink(..) => {
                 worker.total_files.fetch_add(1, Ordering::Relaxed);
             },
             Item::Dir(..) => {
                 worker.total_dirs.fetch_add(1, Ordering::Relaxed);
             },
+            Item::Special(..) => {
+                worker.total_special.fetch_add(1, Ordering::Relaxed);
+            },
         }
 
-        Ok(Some(Self::Item(item, root_id)))
+        Ok(Some(Self::Item(item, root_id, depth, ignores)))
     }
 }
 
@@ -141,30 +243,62 @@ type Handle<'a> = graph::Handle<threaded::Handle<'a, graph::Job<Job>>>;
 #[derive(Debug)]
 pub struct Worker {
     block_size: usize,
+    sample_size: u64,
+    algorithm: digest::Algorithm,
+    symlinks: symlink::Policy,
+    cache: AssertUnwindSafe<Option<cache::Cache>>,
+    filter: filter::Filter,
+    ignore_files: bool,
+    min_depth: usize,
+    max_depth: Option<usize>,
     files_done: AtomicUsize,
     dirs_done: AtomicUsize,
     total_files: AtomicUsize,
     total_dirs: AtomicUsize,
+    total_special: AtomicUsize,
+    /// Combined size of all regular files found
+    total_bytes: AtomicU64,
+    /// Number of bytes scheduled to be read by sampling and hashing jobs
+    bytes_queued: AtomicU64,
+    bytes_hashed: AtomicU64,
+    skipped: summary::Skipped,
+    /// The path most recently picked up by a job, for displaying progress
+    current_path: Mutex<PathBuf>,
     seen: AssertUnwindSafe<DashSet<PathBuf>>,
-    hash_for_path: AssertUnwindSafe<DashMap<PathBuf, file::Hash>>,
-    file_hashes: AssertUnwindSafe<DashMap<file::Hash, HashMap<PathBuf, Metadata>>>,
+    listings: AssertUnwindSafe<DashMap<PathBuf, dir::Listing>>,
+    inodes: AssertUnwindSafe<DashMap<(u64, u64), Bucket>>,
+    size_buckets: AssertUnwindSafe<DashMap<u64, Bucket>>,
+    sample_buckets: AssertUnwindSafe<DashMap<(u64, digest::Hash), Bucket>>,
+    hash_for_path: AssertUnwindSafe<DashMap<PathBuf, digest::Hash>>,
+    file_hashes: AssertUnwindSafe<DashMap<digest::Hash, HashMap<PathBuf, Metadata>>>,
+    hash_for_dir: AssertUnwindSafe<DashMap<PathBuf, digest::Hash>>,
+    dir_hashes: AssertUnwindSafe<DashMap<digest::Hash, dir::DirGroup>>,
+    link_targets: AssertUnwindSafe<DashMap<PathBuf, PathBuf>>,
+    hash_for_link: AssertUnwindSafe<DashMap<PathBuf, digest::Hash>>,
+    link_hashes: AssertUnwindSafe<DashMap<digest::Hash, HashMap<PathBuf, Meta>>>,
+    specials: AssertUnwindSafe<DashMap<PathBuf, Special>>,
+    /// Zero-length files, which are never read or grouped by hash
+    empty_files: AssertUnwindSafe<DashMap<PathBuf, Meta>>,
 }
 
 impl Worker {
     fn tally(&self, job: &Job) -> bool {
-        let path = match job {
-            Job::Item(Item::File(p, _) | Item::Symlink(p, _), _) => {
-                self.files_done.fetch_add(1, Ordering::Relaxed);
-                p
-            },
-            Job::Item(Item::Dir(p, _), _) => {
-                self.dirs_done.fetch_add(1, Ordering::Relaxed);
-                p
-            },
-            Job::FinalizeDir(..) => return true,
+        let (path, done) = match job {
+            Job::Item(Item::File(p, _) | Item::Symlink(p, _), ..) => (p, Some(&self.files_done)),
+            Job::Item(Item::Dir(p, _), ..) => (p, Some(&self.dirs_done)),
+            Job::Item(Item::Special(p, ..), ..) => (p, None),
+            Job::FinalizeDir(..) | Job::SampleFile(..) | Job::HashFile(..) => return true,
         };
 
-        self.seen.insert(path.clone())
+        // A path may be reached more than once by following symlinks, but
+        // should only be counted the first time
+        let first = self.seen.insert(path.clone());
+
+        if let (true, Some(done)) = (first, done) {
+            done.fetch_add(1, Ordering::Relaxed);
+        }
+
+        first
     }
 }
 
@@ -173,6 +307,31 @@ impl Worker {
 #[derive(Debug, Parser)]
 #[clap(version, author)]
 struct Opts {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Search directories for duplicates, printing a report or saving the
+    /// results for use by other commands
+    Scan(ScanOpts),
+    /// Print the duplicates found by a saved scan
+    Report(ReportOpts),
+    /// Apply an action to the duplicate files found by a saved scan
+    Dedupe(DedupeOpts),
+    /// Check that the files found by a saved scan still match their hashes
+    Verify(VerifyOpts),
+    /// Compare the duplicates found by two saved scans
+    Diff(DiffOpts),
+    /// Interactively choose which copies of each duplicate file to keep,
+    /// delete or link, from a saved scan
+    Review(ReviewOpts),
+}
+
+#[derive(Debug, Args)]
+#[allow(clippy::struct_excessive_bools)] // These are independent flags
+struct ScanOpts {
     /// Base directories to search
     #[clap(parse(try_from_os_str = parse_path), required = true)]
     paths: Vec<(PathBuf, Metadata)>,
@@ -185,10 +344,200 @@ struct Opts {
     #[clap(short, long, default_value_t = 4 * 1024 * 1024)]
     block_size: usize,
 
+    /// Number of bytes to read from each end of a large file when checking
+    /// whether it may be identical to another file before hashing it in full.
+    /// Set to 0 to always hash files in full.
+    #[clap(long, default_value_t = 64 * 1024)]
+    sample_size: u64,
+
+    /// Algorithm used to hash file contents
+    #[clap(long = "hash", arg_enum, default_value = "sha512")]
+    algorithm: digest::Algorithm,
+
+    /// How to treat symbolic links
+    #[clap(long, arg_enum, default_value = "skip")]
+    symlinks: symlink::Policy,
+
+    /// List FIFOs, sockets and device files found during the search in the
+    /// report.  These are never opened or hashed.
+    #[clap(long)]
+    list_special: bool,
+
+    /// List empty files found during the search in the report.  Empty files
+    /// are never read, and are not reported as duplicates of one another.
+    #[clap(long)]
+    list_empty: bool,
+
+    /// Leave groups whose files are all hardlinks to the same inode out of the
+    /// report, listing only true duplicates
+    #[clap(long)]
+    hide_hardlinked: bool,
+
     /// Allow the directory search to cross filesystem boundaries.  This is
     /// likely not desirable in most cases.
     #[clap(short = 'x', long)]
     cross_filesystems: bool,
+
+    /// Only search files matching this glob.  Directories are always searched
+    /// unless excluded.  May be given multiple times.
+    #[clap(long, multiple_occurrences = true, number_of_values = 1)]
+    include: Vec<String>,
+
+    /// Skip files and directories matching this glob, along with everything
+    /// inside matching directories.  Globs without a slash match file names,
+    /// and globs with one match the end of the path.  May be given multiple
+    /// times.
+    #[clap(long, multiple_occurrences = true, number_of_values = 1)]
+    exclude: Vec<String>,
+
+    /// Skip files and directories whose full path matches this regular
+    /// expression, along with everything inside matching directories.  May be
+    /// given multiple times.
+    #[clap(long, multiple_occurrences = true, number_of_values = 1)]
+    exclude_regex: Vec<String>,
+
+    /// Skip paths ignored by .gitignore, .ignore and .latkeignore files in
+    /// the searched directories and their parents, as ripgrep does.
+    /// .gitignore files are only used inside a Git repository.
+    #[clap(long)]
+    ignore_files: bool,
+
+    /// Only compare files and directories at least this many levels below a
+    /// search path.  Shallower directories are still searched.
+    #[clap(long, default_value_t = 0)]
+    min_depth: usize,
+
+    /// Don't search more than this many levels below a search path.  Set to 0
+    /// to only compare the search paths themselves.
+    #[clap(long)]
+    max_depth: Option<usize>,
+
+    /// Skip files smaller than this size, such as 1 to skip empty files.
+    /// Accepts binary suffixes like 64K, 1M or 2G.
+    #[clap(long, parse(try_from_str = parse_size))]
+    min_size: Option<u64>,
+
+    /// Skip files larger than this size.  Accepts binary suffixes like 64K, 1M
+    /// or 2G.
+    #[clap(long, parse(try_from_str = parse_size))]
+    max_size: Option<u64>,
+
+    /// Don't display progress while searching.  Progress is only ever shown
+    /// if stderr is a terminal.
+    #[clap(long)]
+    no_progress: bool,
+
+    /// Output format for the duplicate report
+    #[clap(short, long, arg_enum, default_value = "text")]
+    format: report::Format,
+
+    /// Save the results to this file instead of printing a report
+    #[clap(short, long, parse(from_os_str))]
+    output: Option<PathBuf>,
+
+    /// Save the results to an sqlite database at this path instead of printing
+    /// a report.  May be combined with --output.
+    #[clap(long, parse(from_os_str))]
+    sqlite: Option<PathBuf>,
+
+    /// File to store hashes in between runs.  Files whose size, timestamps,
+    /// inode and device are unchanged since the last run are not rehashed.
+    #[clap(short, long, parse(from_os_str))]
+    cache: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+struct ReportOpts {
+    /// Results saved by the scan command
+    #[clap(parse(from_os_str))]
+    results: PathBuf,
+
+    /// List FIFOs, sockets and device files found during the search
+    #[clap(long)]
+    list_special: bool,
+
+    /// List empty files found during the search
+    #[clap(long)]
+    list_empty: bool,
+
+    /// Leave groups whose files are all hardlinks to the same inode out of the
+    /// report
+    #[clap(long)]
+    hide_hardlinked: bool,
+
+    /// Output format for the duplicate report
+    #[clap(short, long, arg_enum, default_value = "text")]
+    format: report::Format,
+}
+
+#[derive(Debug, Args)]
+struct DedupeOpts {
+    /// Results saved by the scan command
+    #[clap(parse(from_os_str))]
+    results: PathBuf,
+
+    /// Action to take on each group of duplicate files
+    #[clap(arg_enum)]
+    action: action::Action,
+
+    /// Print what the action would do without changing any files
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Allow the delete action to remove files.  Without this, the delete
+    /// action only runs with --dry-run, so that its plan must be previewed.
+    #[clap(long)]
+    confirm_delete: bool,
+
+    /// Which file in each group the delete action keeps
+    #[clap(long, arg_enum, default_value = "first-root")]
+    keep: action::Keep,
+
+    /// Prefer keeping files under this directory over any other file, before
+    /// applying --keep.  May be given multiple times, in order of priority.
+    #[clap(long, parse(from_os_str), multiple_occurrences = true, number_of_values = 1)]
+    keep_under: Vec<PathBuf>,
+
+    /// Block size to read files in when comparing them
+    #[clap(short, long, default_value_t = 4 * 1024 * 1024)]
+    block_size: usize,
+}
+
+#[derive(Debug, Args)]
+struct VerifyOpts {
+    /// Results saved by the scan command
+    #[clap(parse(from_os_str))]
+    results: PathBuf,
+
+    /// Block size to read files in
+    #[clap(short, long, default_value_t = 4 * 1024 * 1024)]
+    block_size: usize,
+}
+
+#[derive(Debug, Args)]
+struct DiffOpts {
+    /// Results saved by an earlier scan
+    #[clap(parse(from_os_str))]
+    old: PathBuf,
+
+    /// Results saved by a later scan
+    #[clap(parse(from_os_str))]
+    new: PathBuf,
+}
+
+#[derive(Debug, Args)]
+struct ReviewOpts {
+    /// Results saved by the scan command
+    #[clap(parse(from_os_str))]
+    results: PathBuf,
+
+    /// Print what the plan would do without changing any files
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Block size to read files in when verifying them
+    #[clap(short, long, default_value_t = 4 * 1024 * 1024)]
+    block_size: usize,
 }
 
 fn parse_path(path: &OsStr) -> Result<(PathBuf, Metadata)> {
@@ -198,11 +547,35 @@ fn parse_path(path: &OsStr) -> Result<(PathBuf, Metadata)> {
     Ok((path, meta))
 }
 
+/// Parse a byte count with an optional binary unit suffix, such as `512`,
+/// `64K`, `1M` or `2GiB`
+fn parse_size(s: &str) -> Result<u64> {
+    const UNITS: [&str; 7] = ["", "K", "M", "G", "T", "P", "E"];
+
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, suffix) = s.split_at(split);
+    let num: u64 = num.parse().with_context(|| format!("Invalid size {:?}", s))?;
+
+    let upper = suffix.to_ascii_uppercase();
+    let unit = upper
+        .strip_suffix("IB")
+        .or_else(|| upper.strip_suffix('B'))
+        .unwrap_or(&upper);
+    let Some(power) = UNITS.iter().position(|u| *u == unit) else {
+        bail!("Unknown size suffix {:?}", suffix);
+    };
+
+    1024_u64
+        .checked_pow(u32::try_from(power)?)
+        .and_then(|m| num.checked_mul(m))
+        .ok_or_else(|| anyhow!("Size {:?} is too large", s))
+}
+
 fn main() {
     env_logger::init();
     let opts = Opts::parse();
 
-    match run(opts) {
+    match run(opts.command) {
         Ok(()) => (),
         Err(e) => {
             error!("Program exited with error: {:?}", e);
@@ -211,33 +584,169 @@ fn main() {
     }
 }
 
-fn run(
-    Opts {
-        paths,
+fn run(command: Command) -> Result {
+    match command {
+        Command::Scan(opts) => scan(opts),
+        Command::Report(ReportOpts {
+            results,
+            list_special,
+            list_empty,
+            hide_hardlinked,
+            format,
+        }) => {
+            let mut results = results::Results::load(&results)?;
+
+            if hide_hardlinked {
+                results.groups.retain(|g| g.copies > 1);
+            }
+
+            let specials = if list_special {
+                results.specials()
+            } else {
+                vec![]
+            };
+
+            let empty = if list_empty {
+                results.empty_files()
+            } else {
+                vec![]
+            };
+
+            report::print(&results.groups, &specials, &empty, format)
+        },
+        Command::Dedupe(opts) => dedupe(opts),
+        Command::Verify(VerifyOpts {
+            results,
+            block_size,
+        }) => verify::run(&results::Results::load(&results)?, block_size),
+        Command::Diff(DiffOpts { old, new }) => diff::run(
+            &results::Results::load(&old)?,
+            &results::Results::load(&new)?,
+        ),
+        Command::Review(ReviewOpts {
+            results,
+            dry_run,
+            block_size,
+        }) => review::run(&results, action::Options {
+            dry_run,
+            block_size,
+        }),
+    }
+}
+
+fn dedupe(
+    DedupeOpts {
+        results,
+        action,
+        dry_run,
+        confirm_delete,
+        keep,
+        keep_under,
+        block_size,
+    }: DedupeOpts,
+) -> Result {
+    if action == action::Action::Delete && !dry_run && !confirm_delete {
+        bail!("Refusing to delete files without --confirm-delete; preview with --dry-run first");
+    }
+
+    let results = results::Results::load(&results)?;
+    let keeper = action::Keeper {
+        rule: keep,
+        prefixes: keep_under,
+        roots: results.header.roots,
+    };
+
+    let opts = action::Options {
+        dry_run,
+        block_size,
+    };
+
+    let failed = action::run(action, &results.groups, opts, &keeper);
+
+    if failed > 0 {
+        bail!("Failed to process {} file(s) or group(s)", failed);
+    }
+
+    Ok(())
+}
+
+fn scan(
+    ScanOpts {
+        mut paths,
         threads,
         block_size,
+        sample_size,
+        algorithm,
+        symlinks,
+        list_special,
+        list_empty,
+        hide_hardlinked,
         cross_filesystems,
-    }: Opts,
+        include,
+        exclude,
+        exclude_regex,
+        ignore_files,
+        min_depth,
+        max_depth,
+        min_size,
+        max_size,
+        no_progress,
+        format,
+        output,
+        sqlite,
+        cache,
+    }: ScanOpts,
 ) -> Result {
+    let start = Instant::now();
     let threads = if threads == 0 { None } else { Some(threads) };
 
+    symlink::resolve_roots(symlinks, &mut paths)?;
+    let root_paths: Vec<_> = paths.iter().map(|(p, _)| p.clone()).collect();
+
     let worker = Arc::new(Worker {
         block_size,
+        sample_size,
+        algorithm,
+        symlinks,
+        cache: AssertUnwindSafe(cache.map(cache::Cache::load)),
+        filter: filter::Filter::new(&include, &exclude, &exclude_regex)?
+            .with_size_range(min_size, max_size),
+        ignore_files,
+        min_depth,
+        max_depth,
         files_done: AtomicUsize::new(0),
         dirs_done: AtomicUsize::new(0),
         total_files: AtomicUsize::new(0),
         total_dirs: AtomicUsize::new(0),
+        total_special: AtomicUsize::new(0),
+        total_bytes: AtomicU64::new(0),
+        bytes_queued: AtomicU64::new(0),
+        bytes_hashed: AtomicU64::new(0),
+        skipped: summary::Skipped::default(),
+        current_path: Mutex::new(PathBuf::new()),
         seen: AssertUnwindSafe(DashSet::default()),
+        listings: AssertUnwindSafe(DashMap::default()),
+        inodes: AssertUnwindSafe(DashMap::default()),
+        size_buckets: AssertUnwindSafe(DashMap::default()),
+        sample_buckets: AssertUnwindSafe(DashMap::default()),
         hash_for_path: AssertUnwindSafe(DashMap::default()),
         file_hashes: AssertUnwindSafe(DashMap::default()),
+        hash_for_dir: AssertUnwindSafe(DashMap::default()),
+        dir_hashes: AssertUnwindSafe(DashMap::default()),
+        link_targets: AssertUnwindSafe(DashMap::default()),
+        hash_for_link: AssertUnwindSafe(DashMap::default()),
+        link_hashes: AssertUnwindSafe(DashMap::default()),
+        specials: AssertUnwindSafe(DashMap::default()),
+        empty_files: AssertUnwindSafe(DashMap::default()),
     });
-    let worker2 = worker.clone();
 
-    let pool = threaded::Builder::default()
-        .num_threads(threads)
-        .lifo(true)
-        .build_graph(move |j, h| process(j, h, &worker2).map_err(|e| error!("Job failed: {:?}", e)))
-        .context("Failed to initialize thread pool")?;
+    let progress = if no_progress {
+        None
+    } else {
+        progress::Progress::start(&worker)
+    };
+
+    let mut roots = Vec::with_capacity(paths.len());
 
     for (path, meta) in paths {
         let root_id = if cross_filesystems {
@@ -249,9 +758,88 @@ fn run(
             )
         };
 
-        if let Some(job) = Job::path(path, meta, root_id, &worker)? {
-            pool.push(job);
+        let ignores = if ignore_files {
+            ignores::Stack::for_root(&path)
+        } else {
+            ignores::Stack::default()
+        };
+
+        if let Some(job) = Job::path(path, meta, root_id, 0, ignores, &worker)? {
+            roots.push(job);
+        }
+    }
+
+    // Walk the filesystem first, so that only files whose size collides with
+    // at least one other file need to be read, then narrow down large files
+    // by sampling them before reading them in full
+    run_jobs(threads, &worker, roots)?;
+    run_jobs(threads, &worker, file::sample_jobs(&worker))?;
+    run_jobs(threads, &worker, file::hash_jobs(&worker))?;
+    dir::digest_all(&worker);
+    drop(progress);
+
+    if let Some(ref cache) = *worker.cache {
+        cache.finish(&root_paths, &worker.seen).context("Failed to save cache")?;
+    }
+
+    let mut groups = report::groups(&worker);
+
+    if output.is_some() || sqlite.is_some() {
+        let results = results::Results::new(&worker, root_paths, groups);
+
+        if let Some(output) = output {
+            results.save(&output)?;
+        }
+
+        if let Some(sqlite) = sqlite {
+            sqlite::save(&results, &sqlite)?;
+        }
+
+        summary::print(&worker, &results.groups, start.elapsed());
+    } else {
+        let specials = if list_special {
+            report::specials(&worker)
+        } else {
+            vec![]
+        };
+
+        let empty = if list_empty {
+            report::empty_files(&worker)
+        } else {
+            vec![]
+        };
+
+        if hide_hardlinked {
+            groups.retain(|g| g.copies > 1);
         }
+
+        report::print(&groups, &specials, &empty, format)?;
+        summary::print(&worker, &groups, start.elapsed());
+    }
+
+    Ok(())
+}
+
+fn run_jobs(
+    threads: Option<usize>,
+    worker: &Arc<Worker>,
+    jobs: impl IntoIterator<Item = Job>,
+) -> Result {
+    let worker2 = worker.clone();
+
+    let pool = threaded::Builder::default()
+        .num_threads(threads)
+        .lifo(true)
+        .build_graph(move |j, h| {
+            process(j, h, &worker2).map_err(|e| {
+                worker2.skipped.error(&e);
+                error!("Job failed: {:?}", e);
+            })
+        })
+        .context("Failed to initialize thread pool")?;
+
+    for job in jobs {
+        pool.push(job);
     }
 
     pool.join();
@@ -262,14 +850,87 @@ fn run(
 fn process(job: Job, handle: Handle, worker: &Arc<Worker>) -> Result {
     trace!("{}", job);
 
+    // Progress is best-effort, so never wait for the display to read this
+    if let Ok(mut current) = worker.current_path.try_lock() {
+        current.clone_from(job.item_path());
+    }
+
     if !worker.tally(&job) {
         return Ok(()); // Nothing to do
     }
 
     match job {
-        Job::Item(Item::File(path, meta), _) => file::hash(path, meta, worker),
-        Job::Item(Item::Dir(path, _), root_id) => dir::recurse(path, root_id, handle, worker),
-        Job::Item(Item::Symlink(path, _), _) => bail!("TODO: Handle symlink {:?}", path),
-        Job::FinalizeDir(path, children) => dir::finalize(&path, children, worker),
+        Job::Item(Item::File(path, meta), ..) => {
+            file::bucket(path, meta, worker);
+            Ok(())
+        },
+        Job::Item(Item::Dir(path, meta), root_id, depth, ignores) => {
+            dir::recurse(path, meta, root_id, depth, ignores, handle, worker)
+        },
+        Job::Item(Item::Symlink(path, meta), root_id, depth, _) => match worker.symlinks {
+            symlink::Policy::Skip => {
+                worker.skipped.symlinks.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            },
+            symlink::Policy::Follow => symlink::follow(path, root_id, depth, handle, worker),
+            symlink::Policy::HashTargetPath => symlink::hash_target(path, meta, worker),
+        },
+        Job::Item(Item::Special(path, _, special), ..) => {
+            // Opening a FIFO could block indefinitely, so these are only noted
+            worker.specials.insert(path, special);
+            Ok(())
+        },
+        Job::FinalizeDir(path, meta, children, depth, ignores) => {
+            dir::finalize(path, meta, children, depth, &ignores, worker)
+        },
+        Job::SampleFile(path, meta) => file::sample(path, meta, worker),
+        Job::HashFile(path, meta) => file::hash(path, meta, worker),
+    }
+}
+
+/// The path a file is written to before being renamed over `path`, so that a
+/// failure never leaves a partially written file in its place
+fn staging_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push("~");
+
+    tmp.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn size_suffixes() {
+        assert_eq!(parse_size("0").unwrap(), 0);
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("512B").unwrap(), 512);
+        assert_eq!(parse_size("64K").unwrap(), 64 << 10);
+        assert_eq!(parse_size("64k").unwrap(), 64 << 10);
+        assert_eq!(parse_size("3KB").unwrap(), 3 << 10);
+        assert_eq!(parse_size("1M").unwrap(), 1 << 20);
+        assert_eq!(parse_size("2GiB").unwrap(), 2 << 30);
+        assert_eq!(parse_size("2gib").unwrap(), 2 << 30);
+        assert_eq!(parse_size("5T").unwrap(), 5 << 40);
+        assert_eq!(parse_size("6P").unwrap(), 6 << 50);
+        assert_eq!(parse_size("7E").unwrap(), 7 << 60);
+    }
+
+    #[test]
+    fn invalid_sizes() {
+        for s in ["", "K", "-1", "1.5M", "1 M", "1X", "1KX", "0x10"] {
+            assert!(parse_size(s).is_err(), "{:?} should be rejected", s);
+        }
+    }
+
+    #[test]
+    fn size_overflow() {
+        assert_eq!(parse_size("18446744073709551615").unwrap(), u64::MAX);
+        assert_eq!(parse_size("15E").unwrap(), 15 << 60);
+
+        for s in ["18446744073709551616", "16E", "16777216T", "99999999999999999999K"] {
+            assert!(parse_size(s).is_err(), "{:?} should overflow", s);
+        }
     }
 }
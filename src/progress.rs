@@ -0,0 +1,246 @@
+//! Live progress display for long searches
+
+use std::{
+    io,
+    io::{prelude::*, IsTerminal},
+    sync::{atomic::Ordering, mpsc, Arc},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crossterm::{queue, terminal};
+use log::error;
+
+use crate::Worker;
+
+/// Time between redraws of the progress line
+const INTERVAL: Duration = Duration::from_millis(200);
+
+/// Weight given to the newest measurement when smoothing the read rate
+const SMOOTHING: f64 = 0.1;
+
+/// A background thread periodically redrawing a status line on stderr.  The
+/// line is cleared when this is dropped.
+#[derive(Debug)]
+pub struct Progress {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Progress {
+    /// Start displaying progress, unless stderr is not a terminal
+    pub fn start(worker: &Arc<Worker>) -> Option<Self> {
+        if !io::stderr().is_terminal() {
+            return None;
+        }
+
+        let worker = worker.clone();
+        let (stop, rx) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("progress".into())
+            .spawn(move || {
+                let mut rate = Rate::default();
+
+                while let Err(mpsc::RecvTimeoutError::Timeout) = rx.recv_timeout(INTERVAL) {
+                    rate.update(worker.bytes_hashed.load(Ordering::Relaxed));
+
+                    if let Err(e) = draw(&worker, &rate) {
+                        error!("Failed to draw progress: {:?}", e);
+                        return;
+                    }
+                }
+
+                let mut stderr = io::stderr();
+                let res = write!(stderr, "\r")
+                    .and_then(|()| queue!(stderr, terminal::Clear(terminal::ClearType::CurrentLine)))
+                    .and_then(|()| stderr.flush());
+
+                if let Err(e) = res {
+                    error!("Failed to clear progress: {:?}", e);
+                }
+            });
+
+        match thread {
+            Ok(t) => Some(Self {
+                stop: Some(stop),
+                thread: Some(t),
+            }),
+            Err(e) => {
+                error!("Failed to start progress thread: {:?}", e);
+                None
+            },
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        // Disconnecting the channel stops the thread
+        self.stop.take();
+
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Progress thread panicked");
+            }
+        }
+    }
+}
+
+/// A smoothed measurement of how quickly data is being read
+#[derive(Debug)]
+struct Rate {
+    last_bytes: u64,
+    last_time: Instant,
+    bytes_per_sec: f64,
+}
+
+impl Default for Rate {
+    fn default() -> Self {
+        Self {
+            last_bytes: 0,
+            last_time: Instant::now(),
+            bytes_per_sec: 0.0,
+        }
+    }
+}
+
+impl Rate {
+    #[allow(clippy::cast_precision_loss)]
+    fn update(&mut self, bytes: u64) {
+        let now = Instant::now();
+        let secs = now.duration_since(self.last_time).as_secs_f64();
+
+        if secs <= 0.0 {
+            return;
+        }
+
+        let current = bytes.saturating_sub(self.last_bytes) as f64 / secs;
+
+        // Start from the first real measurement rather than ramping up from
+        // zero, which would make early estimates far too pessimistic
+        self.bytes_per_sec = if self.bytes_per_sec == 0.0 {
+            current
+        } else {
+            SMOOTHING * current + (1.0 - SMOOTHING) * self.bytes_per_sec
+        };
+
+        self.last_bytes = bytes;
+        self.last_time = now;
+    }
+
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    fn bytes_per_sec(&self) -> u64 { self.bytes_per_sec as u64 }
+
+    /// Estimate the time left to read the given number of bytes
+    #[allow(clippy::cast_precision_loss)]
+    fn eta(&self, remaining: u64) -> Option<Duration> {
+        (self.bytes_per_sec >= 1.0)
+            .then(|| Duration::from_secs_f64(remaining as f64 / self.bytes_per_sec))
+    }
+}
+
+fn draw(worker: &Worker, rate: &Rate) -> io::Result<()> {
+    let Worker {
+        ref files_done,
+        ref dirs_done,
+        ref total_files,
+        ref total_dirs,
+        ref total_bytes,
+        ref bytes_queued,
+        ref bytes_hashed,
+        ref current_path,
+        ..
+    } = *worker;
+
+    let queued = bytes_queued.load(Ordering::Relaxed);
+    let hashed = bytes_hashed.load(Ordering::Relaxed);
+
+    // Nothing is queued for reading until the search is complete
+    let reading = if queued == 0 {
+        "searching".into()
+    } else {
+        let eta = rate
+            .eta(queued.saturating_sub(hashed))
+            .map_or_else(String::new, |e| format!(", {} left", human_duration(e)));
+
+        format!(
+            "read {}/{} ({}/s{})",
+            human_bytes(hashed),
+            human_bytes(queued),
+            human_bytes(rate.bytes_per_sec()),
+            eta
+        )
+    };
+
+    let status = format!(
+        "{}/{} files, {}/{} dirs ({}), {} ",
+        files_done.load(Ordering::Relaxed),
+        total_files.load(Ordering::Relaxed),
+        dirs_done.load(Ordering::Relaxed),
+        total_dirs.load(Ordering::Relaxed),
+        human_bytes(total_bytes.load(Ordering::Relaxed)),
+        reading,
+    );
+
+    let width = terminal::size().map_or(80, |(w, _)| usize::from(w));
+    // Leave the last column free so the line never wraps
+    let room = width.saturating_sub(status.chars().count() + 1);
+    let path = current_path
+        .lock()
+        .map(|p| elide(&p.to_string_lossy(), room))
+        .unwrap_or_default();
+
+    let mut stderr = io::stderr().lock();
+    write!(stderr, "\r")?;
+    queue!(stderr, terminal::Clear(terminal::ClearType::CurrentLine))?;
+    let line: String = status.chars().chain(path.chars()).take(width.saturating_sub(1)).collect();
+    write!(stderr, "{}", line)?;
+    stderr.flush()
+}
+
+/// Shorten a string to at most `width` characters by cutting out its start,
+/// which for a path is usually the least interesting part
+fn elide(s: &str, width: usize) -> String {
+    let len = s.chars().count();
+
+    if len <= width {
+        s.into()
+    } else if width == 0 {
+        String::new()
+    } else {
+        std::iter::once('…')
+            .chain(s.chars().skip(len - width + 1))
+            .collect()
+    }
+}
+
+/// Format a duration as hours, minutes and seconds
+fn human_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+/// Format a byte count with a binary unit suffix
+pub fn human_bytes(n: u64) -> String {
+    const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+
+    if n < 1024 {
+        return format!("{} B", n);
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    let mut val = n as f64 / 1024.0;
+    let mut unit = 0;
+
+    while val >= 1024.0 && unit < UNITS.len() - 1 {
+        val /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", val, UNITS[unit])
+}
@@ -0,0 +1,461 @@
+use std::{
+    collections::hash_map,
+    ffi::{OsStr, OsString},
+    fmt,
+    io,
+    io::prelude::*,
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::MetadataExt,
+    },
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use clap::ArgEnum;
+use serde::{
+    de,
+    de::{SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use crate::{
+    digest::{Algorithm, Hash},
+    file,
+    hash::{DashMap, HashMap, HashSet},
+    Meta, Result, Special, Worker,
+};
+
+/// Output format for the duplicate report
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum Format {
+    /// Human-readable listing
+    Text,
+    /// A single JSON array of groups
+    Json,
+    /// One JSON group object per line
+    Ndjson,
+}
+
+/// The type of item a duplicate group contains
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    File,
+    Directory,
+    Symlink,
+}
+
+impl Kind {
+    /// The name of several items of this kind
+    pub fn plural(self) -> &'static str {
+        match self {
+            Self::File => "files",
+            Self::Directory => "directories",
+            Self::Symlink => "symlinks",
+        }
+    }
+}
+
+/// A set of two or more files or directories sharing the same content hash
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Group {
+    pub kind: Kind,
+    pub algorithm: Algorithm,
+    pub hash: Hash,
+    pub size: u64,
+    /// Number of distinct inodes in the group.  Paths sharing an inode are
+    /// hardlinks to one another rather than true duplicates.
+    pub copies: usize,
+    pub files: Vec<Entry>,
+}
+
+/// A single file or directory belonging to a duplicate group
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    #[serde(serialize_with = "serialize_path", deserialize_with = "deserialize_path")]
+    pub path: PathBuf,
+    pub size: u64,
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+    pub inode: u64,
+    pub device: u64,
+}
+
+impl Group {
+    fn new(kind: Kind, algorithm: Algorithm, hash: Hash, mut files: Vec<Entry>) -> Self {
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let copies = files
+            .iter()
+            .map(|f| (f.device, f.inode))
+            .collect::<HashSet<_>>()
+            .len();
+
+        Self {
+            kind,
+            algorithm,
+            hash,
+            size: files[0].size,
+            copies,
+            files,
+        }
+    }
+}
+
+impl Entry {
+    fn new(path: PathBuf, meta: &Meta, size: u64) -> Self {
+        Self {
+            path,
+            size,
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec(),
+            inode: meta.ino(),
+            device: meta.dev(),
+        }
+    }
+}
+
+// Paths are written as strings where possible, and otherwise as arrays of
+// bytes, so that results can be read back and acted on with the exact paths
+pub fn serialize_path<S: Serializer>(path: &Path, ser: S) -> Result<S::Ok, S::Error> {
+    match path.to_str() {
+        Some(s) => ser.serialize_str(s),
+        None => ser.serialize_bytes(path.as_os_str().as_bytes()),
+    }
+}
+
+/// Read a path written by [`serialize_path`]
+pub fn deserialize_path<'de, D: Deserializer<'de>>(de: D) -> Result<PathBuf, D::Error> {
+    struct PathVisitor;
+
+    impl<'de> Visitor<'de> for PathVisitor {
+        type Value = PathBuf;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a path string or an array of bytes")
+        }
+
+        fn visit_str<E: de::Error>(self, s: &str) -> Result<PathBuf, E> { Ok(s.into()) }
+
+        fn visit_bytes<E: de::Error>(self, b: &[u8]) -> Result<PathBuf, E> {
+            Ok(OsStr::from_bytes(b).into())
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<PathBuf, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+
+            while let Some(b) = seq.next_element()? {
+                bytes.push(b);
+            }
+
+            Ok(OsString::from_vec(bytes).into())
+        }
+    }
+
+    de.deserialize_any(PathVisitor)
+}
+
+/// A FIFO, socket or device file found during the search
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename = "special")]
+pub struct SpecialEntry {
+    #[serde(rename = "type")]
+    pub ty: Special,
+    #[serde(serialize_with = "serialize_path", deserialize_with = "deserialize_path")]
+    pub path: PathBuf,
+}
+
+/// A zero-length file found during the search
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename = "empty")]
+pub struct EmptyEntry {
+    #[serde(serialize_with = "serialize_path", deserialize_with = "deserialize_path")]
+    pub path: PathBuf,
+}
+
+/// Collect all special files found, sorted by path
+pub fn specials(worker: impl AsRef<Worker>) -> Vec<SpecialEntry> {
+    let Worker { ref specials, .. } = *worker.as_ref();
+
+    let mut specials: Vec<_> = specials
+        .iter()
+        .map(|e| SpecialEntry {
+            ty: *e.value(),
+            path: e.key().clone(),
+        })
+        .collect();
+
+    specials.sort_by(|a, b| a.path.cmp(&b.path));
+
+    specials
+}
+
+/// Collect all empty files found, sorted by path
+pub fn empty_files(worker: impl AsRef<Worker>) -> Vec<EmptyEntry> {
+    let Worker { ref empty_files, .. } = *worker.as_ref();
+
+    let mut empty: Vec<_> = empty_files
+        .iter()
+        .map(|e| EmptyEntry {
+            path: e.key().clone(),
+        })
+        .collect();
+
+    empty.sort_by(|a, b| a.path.cmp(&b.path));
+
+    empty
+}
+
+/// Collect all duplicate groups, sorted by descending size, then by kind and
+/// hash, with the entries in each group sorted by path.
+///
+/// Directory groups are omitted if they contain no data, or if every directory
+/// in the group is inside a directory that is itself a duplicate of the
+/// others' parents.
+pub fn groups(worker: impl AsRef<Worker>) -> Vec<Group> {
+    let Worker {
+        algorithm,
+        ref inodes,
+        ref hash_for_path,
+        ref file_hashes,
+        ref hash_for_dir,
+        ref dir_hashes,
+        ref link_hashes,
+        ..
+    } = *worker.as_ref();
+
+    let simple = |kind, map: &DashMap<Hash, HashMap<PathBuf, Meta>>| {
+        map.iter()
+            .filter(|e| e.value().len() > 1)
+            .map(|e| {
+                let files = e
+                    .value()
+                    .iter()
+                    .map(|(p, m)| Entry::new(p.clone(), m, m.len()))
+                    .collect();
+
+                Group::new(kind, algorithm, e.key().clone(), files)
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // Hardlinks to a file that was never read, since nothing else shares its
+    // size, are grouped by inode rather than by content
+    let hardlinks = inodes
+        .iter()
+        .filter(|e| {
+            let (ref path, ref meta) = e.value()[0];
+
+            e.value().len() > 1 && meta.len() != 0 && !hash_for_path.contains_key(path)
+        })
+        .map(|e| {
+            let files = e
+                .value()
+                .iter()
+                .map(|(p, m)| Entry::new(p.clone(), m, m.len()))
+                .collect();
+
+            Group::new(
+                Kind::File,
+                algorithm,
+                file::inode_hash(algorithm, &e.value()[0].1),
+                files,
+            )
+        });
+
+    let dirs = dir_hashes
+        .iter()
+        .filter(|e| e.value().dirs.len() > 1 && e.value().size != 0)
+        .filter(|e| {
+            let parents: HashSet<_> = e.value().dirs.keys().filter_map(|p| p.parent()).collect();
+            let mut hashes = parents
+                .iter()
+                .map(|p| hash_for_dir.get(*p).map(|h| h.value().clone()));
+            let first = hashes.next().flatten();
+
+            // Siblings are never redundant, since their parent is not a
+            // duplicate of itself
+            parents.len() != e.value().dirs.len() || first.is_none() || hashes.any(|h| h != first)
+        })
+        .map(|e| {
+            let size = e.value().size;
+            let dirs = e
+                .value()
+                .dirs
+                .iter()
+                .map(|(p, m)| Entry::new(p.clone(), m, size))
+                .collect();
+
+            Group::new(Kind::Directory, algorithm, e.key().clone(), dirs)
+        });
+
+    let mut groups: Vec<_> = simple(Kind::File, file_hashes)
+        .into_iter()
+        .chain(hardlinks)
+        .chain(dirs)
+        .chain(simple(Kind::Symlink, link_hashes))
+        .collect();
+
+    groups.sort_by(|a, b| {
+        b.size
+            .cmp(&a.size)
+            .then_with(|| a.kind.cmp(&b.kind))
+            .then_with(|| a.hash.cmp(&b.hash))
+    });
+
+    groups
+}
+
+/// Print the duplicate groups, special files and empty files to stdout in the
+/// given format
+pub fn print(
+    groups: &[Group],
+    specials: &[SpecialEntry],
+    empty: &[EmptyEntry],
+    format: Format,
+) -> Result {
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+
+    match format {
+        Format::Text => write_text(&mut out, groups, specials, empty),
+        Format::Json => write_json(&mut out, groups, specials, empty),
+        Format::Ndjson => write_ndjson(&mut out, groups, specials, empty),
+    }
+    .and_then(|()| out.flush())
+    .context("Failed to write report")
+}
+
+fn write_text(
+    mut out: impl Write,
+    groups: &[Group],
+    specials: &[SpecialEntry],
+    empty: &[EmptyEntry],
+) -> io::Result<()> {
+    for (
+        i,
+        Group {
+            kind,
+            algorithm,
+            hash,
+            size,
+            copies,
+            files,
+        },
+    ) in groups.iter().enumerate()
+    {
+        if i != 0 {
+            writeln!(out)?;
+        }
+
+        write!(
+            out,
+            "{}:{} ({} bytes, {} {}",
+            algorithm,
+            hash,
+            size,
+            files.len(),
+            kind.plural()
+        )?;
+
+        match *copies {
+            1 => writeln!(out, ", already hardlinked)")?,
+            c if c == files.len() => writeln!(out, ")")?,
+            c => writeln!(out, ", {} copies)", c)?,
+        }
+
+        let mut inodes = HashMap::default();
+
+        for Entry {
+            path,
+            inode,
+            device,
+            ..
+        } in files
+        {
+            match inodes.entry((device, inode)) {
+                hash_map::Entry::Vacant(v) => {
+                    v.insert(path);
+                    writeln!(out, "  {}", path.display())?;
+                },
+                hash_map::Entry::Occupied(o) => writeln!(
+                    out,
+                    "  {} (hardlink of {})",
+                    path.display(),
+                    o.get().display()
+                )?,
+            }
+        }
+    }
+
+    if !specials.is_empty() {
+        if !groups.is_empty() {
+            writeln!(out)?;
+        }
+
+        writeln!(out, "Special files ({}):", specials.len())?;
+
+        for SpecialEntry { ty, path } in specials {
+            writeln!(out, "  {}: {}", ty, path.display())?;
+        }
+    }
+
+    if !empty.is_empty() {
+        if !groups.is_empty() || !specials.is_empty() {
+            writeln!(out)?;
+        }
+
+        writeln!(out, "Empty files ({}):", empty.len())?;
+
+        for EmptyEntry { path } in empty {
+            writeln!(out, "  {}", path.display())?;
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum JsonItem<'a> {
+    Group(&'a Group),
+    Special(&'a SpecialEntry),
+    Empty(&'a EmptyEntry),
+}
+
+fn json_items<'a>(
+    groups: &'a [Group],
+    specials: &'a [SpecialEntry],
+    empty: &'a [EmptyEntry],
+) -> impl Iterator<Item = JsonItem<'a>> {
+    groups
+        .iter()
+        .map(JsonItem::Group)
+        .chain(specials.iter().map(JsonItem::Special))
+        .chain(empty.iter().map(JsonItem::Empty))
+}
+
+fn write_json(
+    mut out: impl Write,
+    groups: &[Group],
+    specials: &[SpecialEntry],
+    empty: &[EmptyEntry],
+) -> io::Result<()> {
+    serde_json::to_writer(&mut out, &json_items(groups, specials, empty).collect::<Vec<_>>())?;
+    writeln!(out)
+}
+
+fn write_ndjson(
+    mut out: impl Write,
+    groups: &[Group],
+    specials: &[SpecialEntry],
+    empty: &[EmptyEntry],
+) -> io::Result<()> {
+    for item in json_items(groups, specials, empty) {
+        serde_json::to_writer(&mut out, &item)?;
+        writeln!(out)?;
+    }
+
+    Ok(())
+}
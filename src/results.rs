@@ -0,0 +1,424 @@
+//! Scan results saved for later use by other commands.
+//!
+//! A results file consists of two JSON documents, one after the other: a
+//! [`Header`] identifying the file and the version of its layout, followed by
+//! the [`Body`] holding everything the scan found.  The header is checked
+//! before anything else is read, so that files written by an incompatible
+//! version of latke are rejected with a clear error rather than failing to
+//! parse partway through.
+
+use std::{
+    fs,
+    fs::File,
+    io,
+    io::{prelude::*, BufReader, BufWriter},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Context};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+
+use crate::{
+    digest::{Algorithm, Hash},
+    report,
+    report::{EmptyEntry, Group, SpecialEntry},
+    Item, Meta, Result, Special, Worker,
+};
+
+/// Identifies a file as latke results
+const FORMAT: &str = "latke-results";
+
+/// Bumped whenever the layout of the results file changes
+const VERSION: u32 = 1;
+
+/// Describes a results file and the scan that produced it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Header {
+    pub format: String,
+    pub version: u32,
+    /// The version of latke that wrote the file
+    pub latke_version: String,
+    /// When the scan finished, in seconds since the Unix epoch
+    pub created: u64,
+    pub algorithm: Algorithm,
+    /// The search paths, in the order they were given
+    #[serde(serialize_with = "serialize_paths", deserialize_with = "deserialize_paths")]
+    pub roots: Vec<PathBuf>,
+}
+
+/// The type of an [`Item`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ItemType {
+    File,
+    Directory,
+    Symlink,
+    Fifo,
+    Socket,
+    BlockDevice,
+    CharDevice,
+}
+
+/// A single path found by the scan
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ItemRecord {
+    #[serde(
+        serialize_with = "report::serialize_path",
+        deserialize_with = "report::deserialize_path"
+    )]
+    pub path: PathBuf,
+    #[serde(rename = "type")]
+    pub ty: ItemType,
+    pub size: u64,
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+    pub inode: u64,
+    pub device: u64,
+    /// The content hash of a file, digest of a directory or hash of a
+    /// symlink's target path, if one was computed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<Hash>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Body {
+    items: Vec<ItemRecord>,
+    groups: Vec<Group>,
+}
+
+/// Everything a scan found, as needed to report on or act on it later
+#[derive(Debug)]
+pub struct Results {
+    pub header: Header,
+    /// Every file, directory, symlink and special file found, sorted by path
+    pub items: Vec<ItemRecord>,
+    pub groups: Vec<Group>,
+}
+
+impl ItemRecord {
+    fn new(path: PathBuf, meta: &Meta, ty: ItemType, size: u64, hash: Option<Hash>) -> Self {
+        use std::os::unix::fs::MetadataExt;
+
+        Self {
+            path,
+            ty,
+            size,
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec(),
+            inode: meta.ino(),
+            device: meta.dev(),
+            hash,
+        }
+    }
+}
+
+impl Results {
+    /// Collect the results of a completed scan
+    pub fn new(worker: impl AsRef<Worker>, roots: Vec<PathBuf>, groups: Vec<Group>) -> Self {
+        let Worker {
+            algorithm,
+            ref seen,
+            ref listings,
+            ref inodes,
+            ref hash_for_path,
+            ref hash_for_dir,
+            ref dir_hashes,
+            ref hash_for_link,
+            ..
+        } = *worker.as_ref();
+
+        let mut items = vec![];
+
+        for links in inodes.iter() {
+            for (path, meta) in links.value() {
+                let hash = hash_for_path.get(path).map(|h| h.value().clone());
+                items.push(ItemRecord::new(
+                    path.clone(),
+                    meta,
+                    ItemType::File,
+                    meta.len(),
+                    hash,
+                ));
+            }
+        }
+
+        for listing in listings.iter() {
+            let hash = hash_for_dir.get(listing.key()).map(|h| h.value().clone());
+            // As in the report, a directory's size is that of its contents
+            let size = hash
+                .as_ref()
+                .and_then(|h| dir_hashes.get(h))
+                .map_or(0, |g| g.size);
+            items.push(ItemRecord::new(
+                listing.key().clone(),
+                &listing.meta,
+                ItemType::Directory,
+                size,
+                hash,
+            ));
+
+            // Symlinks and special files are only recorded as the children of
+            // the directories containing them
+            for child in &listing.children {
+                let (path, meta, ty) = match child {
+                    Item::Symlink(p, m) => (p, m, ItemType::Symlink),
+                    Item::Special(p, m, s) => (p, m, match s {
+                        Special::Fifo => ItemType::Fifo,
+                        Special::Socket => ItemType::Socket,
+                        Special::BlockDevice => ItemType::BlockDevice,
+                        Special::CharDevice => ItemType::CharDevice,
+                    }),
+                    Item::File(..) | Item::Dir(..) => continue,
+                };
+
+                if seen.contains(path) {
+                    let hash = hash_for_link.get(path).map(|h| h.value().clone());
+                    items.push(ItemRecord::new(path.clone(), meta, ty, meta.len(), hash));
+                }
+            }
+        }
+
+        items.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+
+        Self {
+            header: Header {
+                format: FORMAT.into(),
+                version: VERSION,
+                latke_version: env!("CARGO_PKG_VERSION").into(),
+                created,
+                algorithm,
+                roots,
+            },
+            items,
+            groups,
+        }
+    }
+
+    /// All special files found, sorted by path
+    pub fn specials(&self) -> Vec<SpecialEntry> {
+        self.items
+            .iter()
+            .filter_map(|i| {
+                let ty = match i.ty {
+                    ItemType::Fifo => Special::Fifo,
+                    ItemType::Socket => Special::Socket,
+                    ItemType::BlockDevice => Special::BlockDevice,
+                    ItemType::CharDevice => Special::CharDevice,
+                    ItemType::File | ItemType::Directory | ItemType::Symlink => return None,
+                };
+
+                Some(SpecialEntry {
+                    ty,
+                    path: i.path.clone(),
+                })
+            })
+            .collect()
+    }
+
+    /// All empty files found, sorted by path
+    pub fn empty_files(&self) -> Vec<EmptyEntry> {
+        self.items
+            .iter()
+            .filter(|i| i.ty == ItemType::File && i.size == 0)
+            .map(|i| EmptyEntry {
+                path: i.path.clone(),
+            })
+            .collect()
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("Failed to open results {:?}", path))?;
+        let mut de = serde_json::Deserializer::from_reader(BufReader::new(file));
+
+        // Only the header is parsed into a generic value, since the body may
+        // be very large
+        let header = match Value::deserialize(&mut de) {
+            Ok(h) => h,
+            Err(e) if e.is_eof() => bail!("{:?} is empty or truncated", path),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to parse header of {:?}", path))
+            },
+        };
+
+        // Check the format and version before anything else, since the rest
+        // of the header may not even parse if either is wrong
+        let field = |name| header.get(name);
+        let version = match (field("format").and_then(Value::as_str), field("version")) {
+            (Some(FORMAT), Some(v)) => v.as_u64(),
+            _ => bail!("{:?} is not a latke results file", path),
+        };
+
+        match version {
+            Some(v) if v == u64::from(VERSION) => (),
+            Some(v) if v < u64::from(VERSION) => bail!(
+                "{:?} uses results format version {}, which is no longer supported; please scan \
+                 again",
+                path,
+                v
+            ),
+            v => bail!(
+                "{:?} uses results format version {} (written by latke {}), but this version of \
+                 latke only supports version {}",
+                path,
+                v.map_or_else(|| "unknown".into(), |v| v.to_string()),
+                field("latke_version").and_then(Value::as_str).unwrap_or("unknown"),
+                VERSION
+            ),
+        }
+
+        let header: Header = serde_json::from_value(header)
+            .with_context(|| format!("Failed to parse header of {:?}", path))?;
+
+        let body = match Body::deserialize(&mut de) {
+            Ok(b) => b,
+            Err(e) if e.is_eof() => bail!("{:?} is truncated", path),
+            Err(e) => return Err(e).with_context(|| format!("Failed to parse results {:?}", path)),
+        };
+
+        de.end().map_err(|_| anyhow!("{:?} has unexpected trailing data", path))?;
+
+        Ok(Self {
+            header,
+            items: body.items,
+            groups: body.groups,
+        })
+    }
+
+    /// Atomically write the results to `path`
+    pub fn save(&self, path: &Path) -> Result {
+        #[derive(Serialize)]
+        struct BodyRef<'a> {
+            items: &'a [ItemRecord],
+            groups: &'a [Group],
+        }
+
+        let tmp = crate::staging_path(path);
+        let mut file = BufWriter::new(
+            File::create(&tmp).with_context(|| format!("Failed to create {:?}", tmp))?,
+        );
+
+        serde_json::to_writer(&mut file, &self.header)
+            .map_err(io::Error::from)
+            .and_then(|()| file.write_all(b"\n"))
+            .and_then(|()| {
+                serde_json::to_writer(&mut file, &BodyRef {
+                    items: &self.items,
+                    groups: &self.groups,
+                })
+                .map_err(io::Error::from)
+            })
+            .and_then(|()| file.write_all(b"\n"))
+            .with_context(|| format!("Failed to write {:?}", tmp))?;
+
+        file.into_inner()
+            .map_err(io::IntoInnerError::into_error)
+            .and_then(|f| f.sync_all())
+            .with_context(|| format!("Failed to write {:?}", tmp))?;
+
+        fs::rename(&tmp, path).with_context(|| format!("Failed to move results to {:?}", path))
+    }
+}
+
+fn serialize_paths<S: Serializer>(paths: &[PathBuf], ser: S) -> Result<S::Ok, S::Error> {
+    struct PathRef<'a>(&'a Path);
+
+    impl Serialize for PathRef<'_> {
+        fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+            report::serialize_path(self.0, ser)
+        }
+    }
+
+    ser.collect_seq(paths.iter().map(|p| PathRef(p)))
+}
+
+fn deserialize_paths<'de, D: Deserializer<'de>>(de: D) -> Result<Vec<PathBuf>, D::Error> {
+    #[derive(Deserialize)]
+    struct PathBox(#[serde(deserialize_with = "report::deserialize_path")] PathBuf);
+
+    Vec::<PathBox>::deserialize(de).map(|v| v.into_iter().map(|p| p.0).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write `contents` to a temporary file and try to load it as results
+    fn load(name: &str, contents: &str) -> Result<Results> {
+        let path = std::env::temp_dir()
+            .join(format!("latke-test-{}-{}.json", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+
+        let res = Results::load(&path);
+        fs::remove_file(&path).unwrap();
+        res
+    }
+
+    fn header(version: u32) -> String {
+        serde_json::json!({
+            "format": FORMAT,
+            "version": version,
+            "latke_version": "9.9.9",
+            "created": 0,
+            "algorithm": "blake3",
+            "roots": ["a"],
+        })
+        .to_string()
+    }
+
+    const BODY: &str = r#"{"items":[],"groups":[]}"#;
+
+    fn error(name: &str, contents: &str) -> String {
+        load(name, contents).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn current_version() {
+        let results = load("current", &format!("{}\n{}\n", header(VERSION), BODY)).unwrap();
+
+        assert_eq!(results.header.roots, [PathBuf::from("a")]);
+        assert!(results.items.is_empty());
+        assert!(results.groups.is_empty());
+    }
+
+    #[test]
+    fn other_versions() {
+        let newer = error("newer", &format!("{}\n{}\n", header(VERSION + 1), BODY));
+        assert!(newer.contains("written by latke 9.9.9"), "{}", newer);
+
+        let older = error("older", &format!("{}\n{}\n", header(VERSION - 1), BODY));
+        assert!(older.contains("no longer supported"), "{}", older);
+    }
+
+    #[test]
+    fn other_files() {
+        for contents in [r#"{"format":"other","version":1}"#, r#"{"version":1}"#, "[1, 2]"] {
+            let e = error("other", contents);
+            assert!(e.contains("is not a latke results file"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        let e = error("empty", "");
+        assert!(e.contains("is empty or truncated"), "{}", e);
+
+        let header = header(VERSION);
+        let e = error("partial-header", &header[..header.len() / 2]);
+        assert!(e.contains("is empty or truncated"), "{}", e);
+
+        let e = error("no-body", &header);
+        assert!(e.contains("is truncated"), "{}", e);
+
+        let e = error("partial-body", &format!("{}\n{}", header, &BODY[..10]));
+        assert!(e.contains("is truncated"), "{}", e);
+
+        let e = error("trailing", &format!("{}\n{}\n{}", header, BODY, BODY));
+        assert!(e.contains("unexpected trailing data"), "{}", e);
+    }
+}
@@ -0,0 +1,370 @@
+//! Interactive review of saved scan results
+
+use std::{
+    io,
+    io::{prelude::*, IsTerminal},
+    path::Path,
+};
+
+use anyhow::{bail, Context};
+use crossterm::{
+    cursor,
+    event::{self, Event, KeyCode, KeyEvent, KeyModifiers},
+    execute, queue, style, terminal,
+};
+use log::error;
+
+use crate::{
+    action,
+    report::{Entry, Group, Kind},
+    results::Results,
+    Result,
+};
+
+const HELP: &str =
+    "Up/Down: select  Left/Right: group  k: keep  d: delete  l: link  a: apply  q: quit";
+
+/// What to do with a single file in a group
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mark {
+    Keep,
+    Delete,
+    /// Replace with a hardlink to a kept file on the same device
+    Link,
+}
+
+impl Mark {
+    fn label(self) -> &'static str {
+        match self {
+            Self::Keep => "keep",
+            Self::Delete => "delete",
+            Self::Link => "link",
+        }
+    }
+}
+
+/// Puts the terminal into raw mode on an alternate screen, restoring it when
+/// dropped so that a failure never leaves the terminal unusable
+struct Screen;
+
+impl Screen {
+    fn enter() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+
+        // Construct this first so the raw mode is undone if the rest fails
+        let screen = Self;
+        execute!(io::stdout(), terminal::EnterAlternateScreen, cursor::Hide)?;
+
+        Ok(screen)
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        if let Err(e) = execute!(io::stdout(), terminal::LeaveAlternateScreen, cursor::Show)
+            .and_then(|()| terminal::disable_raw_mode())
+        {
+            error!("Failed to restore terminal: {:?}", e);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Review {
+    groups: Vec<Group>,
+    marks: Vec<Vec<Mark>>,
+    group: usize,
+    entry: usize,
+    scroll: usize,
+    status: String,
+}
+
+/// Load saved results and let the user choose what to do with each copy of
+/// every duplicate file, then apply their choices
+pub fn run(results: &Path, opts: action::Options) -> Result {
+    let groups: Vec<_> = Results::load(results)?
+        .groups
+        .into_iter()
+        .filter(|g| g.kind == Kind::File && g.copies > 1)
+        .collect();
+
+    if groups.is_empty() {
+        eprintln!("No duplicate files to review in {:?}", results);
+        return Ok(());
+    }
+
+    if !io::stdin().is_terminal() || !io::stdout().is_terminal() {
+        bail!("Reviewing a report requires an interactive terminal");
+    }
+
+    let mut review = Review {
+        marks: groups.iter().map(|g| vec![Mark::Keep; g.files.len()]).collect(),
+        groups,
+        group: 0,
+        entry: 0,
+        scroll: 0,
+        status: String::new(),
+    };
+
+    let confirmed = {
+        let _screen = Screen::enter().context("Failed to set up terminal")?;
+
+        review.interact().context("Failed to run review")?
+    };
+
+    if !confirmed {
+        eprintln!("Review cancelled, no files changed");
+        return Ok(());
+    }
+
+    let failed = review.apply(opts);
+
+    if failed > 0 {
+        bail!("Failed to process {} file(s) or group(s)", failed);
+    }
+
+    Ok(())
+}
+
+impl Review {
+    /// Handle input until the user applies or abandons their plan, returning
+    /// true if it should be applied
+    fn interact(&mut self) -> io::Result<bool> {
+        let mut out = io::stdout();
+
+        loop {
+            self.draw(&mut out)?;
+
+            let Event::Key(KeyEvent { code, modifiers }) = event::read()? else {
+                continue;
+            };
+
+            self.status.clear();
+
+            match code {
+                KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => {
+                    return Ok(false)
+                },
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(false),
+                KeyCode::Up => self.entry = self.entry.saturating_sub(1),
+                KeyCode::Down => {
+                    self.entry = (self.entry + 1).min(self.groups[self.group].files.len() - 1);
+                },
+                KeyCode::Left | KeyCode::PageUp => self.select_group(self.group.saturating_sub(1)),
+                KeyCode::Right | KeyCode::PageDown => {
+                    self.select_group((self.group + 1).min(self.groups.len() - 1));
+                },
+                KeyCode::Char('k') => self.mark(Mark::Keep),
+                KeyCode::Char('d') => self.mark(Mark::Delete),
+                KeyCode::Char('l') => self.mark(Mark::Link),
+                KeyCode::Char('a') => match self.validate() {
+                    Ok(()) => return Ok(true),
+                    Err((group, msg)) => {
+                        self.select_group(group);
+                        self.status = msg;
+                    },
+                },
+                _ => (),
+            }
+        }
+    }
+
+    fn select_group(&mut self, group: usize) {
+        if group != self.group {
+            self.group = group;
+            self.entry = 0;
+            self.scroll = 0;
+        }
+    }
+
+    fn mark(&mut self, mark: Mark) {
+        self.marks[self.group][self.entry] = mark;
+        self.entry = (self.entry + 1).min(self.groups[self.group].files.len() - 1);
+    }
+
+    /// Ensure every group that changes anything keeps at least one copy, and
+    /// that every file to be linked has a kept file on its device to link to.
+    /// Files that are already hardlinks to a kept file need nothing to link
+    /// to.  On failure, returns the offending group and a description.
+    fn validate(&self) -> Result<(), (usize, String)> {
+        for (i, (group, marks)) in self.groups.iter().zip(&self.marks).enumerate() {
+            let kept: Vec<_> = group
+                .files
+                .iter()
+                .zip(marks)
+                .filter(|(_, m)| **m == Mark::Keep)
+                .map(|(e, _)| e)
+                .collect();
+
+            if kept.is_empty() {
+                return Err((i, "At least one copy must be kept".into()));
+            }
+
+            for (entry, _) in group
+                .files
+                .iter()
+                .zip(marks)
+                .filter(|(e, m)| **m == Mark::Link && !is_kept(&kept, e))
+            {
+                if !kept.iter().any(|k| k.device == entry.device) {
+                    return Err((
+                        i,
+                        format!("No kept copy on the same device as {}", entry.path.display()),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn draw(&mut self, out: &mut impl Write) -> io::Result<()> {
+        let (width, height) = terminal::size()?;
+        let width = usize::from(width);
+        let group = &self.groups[self.group];
+        let marks = &self.marks[self.group];
+
+        // Two header lines and a blank line above the list, and a status line
+        // and help line below it
+        let rows = usize::from(height).saturating_sub(5).max(1);
+
+        if self.entry < self.scroll {
+            self.scroll = self.entry;
+        } else if self.entry >= self.scroll + rows {
+            self.scroll = self.entry + 1 - rows;
+        }
+
+        queue!(out, terminal::Clear(terminal::ClearType::All))?;
+
+        let header = format!(
+            "Group {}/{}: {} bytes, {} files, {} copies",
+            self.group + 1,
+            self.groups.len(),
+            group.size,
+            group.files.len(),
+            group.copies
+        );
+        line(out, 0, &header, width)?;
+        line(out, 1, &format!("{}:{}", group.algorithm, group.hash), width)?;
+
+        for ((i, (entry, mark)), row) in group
+            .files
+            .iter()
+            .zip(marks)
+            .enumerate()
+            .skip(self.scroll)
+            .take(rows)
+            .zip(3..)
+        {
+            let text = format!(
+                "{} [{:<6}] {}",
+                if i == self.entry { '>' } else { ' ' },
+                mark.label(),
+                entry.path.display()
+            );
+
+            if i == self.entry {
+                queue!(out, style::SetAttribute(style::Attribute::Reverse))?;
+                line(out, row, &text, width)?;
+                queue!(out, style::SetAttribute(style::Attribute::Reset))?;
+            } else {
+                line(out, row, &text, width)?;
+            }
+        }
+
+        line(out, height.saturating_sub(2), &self.status, width)?;
+        line(out, height.saturating_sub(1), HELP, width)?;
+
+        out.flush()
+    }
+
+    /// Carry out the plan, reporting failures per file.  Returns the number of
+    /// files and groups that could not be processed.
+    fn apply(&self, opts: action::Options) -> usize {
+        let (mut deleted, mut linked, mut bytes) = (0_usize, 0_usize, 0_u64);
+        let mut failed = 0;
+
+        for (group, marks) in self.groups.iter().zip(&self.marks) {
+            if marks.iter().all(|m| *m == Mark::Keep) {
+                continue;
+            }
+
+            let kept: Vec<_> = group
+                .files
+                .iter()
+                .zip(marks)
+                .filter(|(_, m)| **m == Mark::Keep)
+                .map(|(e, _)| e)
+                .collect();
+
+            if let Some(e) = kept.iter().find_map(|k| action::check_unchanged(k).err()) {
+                error!("Skipping group {}: {:?}", group.hash, e);
+                failed += 1;
+                continue;
+            }
+
+            let mut links = action::Links::new(&group.files);
+
+            for (entry, mark) in group.files.iter().zip(marks) {
+                let res = match mark {
+                    Mark::Keep => continue,
+                    // Already a hardlink to a kept file, so there is nothing
+                    // to replace
+                    Mark::Link if is_kept(&kept, entry) => continue,
+                    Mark::Delete => action::delete(kept[0], entry, opts),
+                    Mark::Link => link_to(&kept, entry, opts),
+                };
+
+                match res {
+                    Ok(()) => {
+                        match mark {
+                            Mark::Keep => unreachable!(),
+                            Mark::Delete => deleted += 1,
+                            Mark::Link => linked += 1,
+                        }
+
+                        bytes += links.remove(entry);
+                    },
+                    Err(e) => {
+                        error!("Failed to {} {:?}: {:?}", mark.label(), entry.path, e);
+                        failed += 1;
+                    },
+                }
+            }
+        }
+
+        eprintln!(
+            "{} {} file(s) and {} {} file(s), freeing {} byte(s)",
+            if opts.dry_run { "Would delete" } else { "Deleted" },
+            deleted,
+            if opts.dry_run { "link" } else { "linked" },
+            linked,
+            bytes
+        );
+
+        failed
+    }
+}
+
+/// Whether a file shares its inode with any kept file
+fn is_kept(kept: &[&Entry], entry: &Entry) -> bool {
+    kept.iter().any(|k| (k.device, k.inode) == (entry.device, entry.inode))
+}
+
+/// Link a file to the first kept file on its device that is not already the
+/// same inode
+fn link_to(kept: &[&Entry], entry: &Entry, opts: action::Options) -> Result {
+    let keep = kept
+        .iter()
+        .find(|k| k.device == entry.device && k.inode != entry.inode)
+        .context("No kept copy on the same device")?;
+
+    action::link(keep, entry, opts)
+}
+
+/// Write a single line of text at the given row, truncated to fit
+fn line(out: &mut impl Write, row: u16, text: &str, width: usize) -> io::Result<()> {
+    let text: String = text.chars().take(width).collect();
+
+    queue!(out, cursor::MoveTo(0, row), style::Print(text))
+}
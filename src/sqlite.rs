@@ -0,0 +1,240 @@
+//! Scan results written as a `SQLite` database for ad-hoc querying
+
+use std::{
+    fs,
+    io,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+
+use anyhow::Context;
+use log::error;
+use rusqlite::{
+    params,
+    types::{ToSqlOutput, ValueRef},
+    Connection, Transaction,
+};
+
+use crate::{
+    digest::Hash,
+    hash::{HashMap, HashSet},
+    results::{ItemRecord, ItemType, Results},
+    Result,
+};
+
+const SCHEMA: &str = "
+CREATE TABLE scan (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+
+CREATE TABLE roots (
+    position INTEGER PRIMARY KEY,
+    path TEXT NOT NULL
+);
+
+CREATE TABLE hashes (
+    id INTEGER PRIMARY KEY,
+    kind TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    paths INTEGER NOT NULL,
+    copies INTEGER NOT NULL,
+    UNIQUE (kind, hash)
+);
+
+CREATE TABLE files (
+    id INTEGER PRIMARY KEY,
+    path TEXT NOT NULL UNIQUE,
+    type TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    mtime INTEGER NOT NULL,
+    mtime_nsec INTEGER NOT NULL,
+    inode INTEGER NOT NULL,
+    device INTEGER NOT NULL,
+    hash_id INTEGER REFERENCES hashes (id)
+);
+
+CREATE TABLE directories (
+    id INTEGER PRIMARY KEY,
+    path TEXT NOT NULL UNIQUE,
+    size INTEGER NOT NULL,
+    mtime INTEGER NOT NULL,
+    mtime_nsec INTEGER NOT NULL,
+    inode INTEGER NOT NULL,
+    device INTEGER NOT NULL,
+    hash_id INTEGER REFERENCES hashes (id)
+);
+
+CREATE INDEX hashes_copies ON hashes (copies, size);
+CREATE INDEX files_hash ON files (hash_id);
+CREATE INDEX files_size ON files (size);
+CREATE INDEX files_inode ON files (device, inode);
+CREATE INDEX directories_hash ON directories (hash_id);
+";
+
+/// Paths and inodes sharing a hash
+#[derive(Debug, Default)]
+struct HashInfo {
+    size: u64,
+    paths: usize,
+    inodes: HashSet<(u64, u64)>,
+}
+
+/// Write the results to a new `SQLite` database at `path`, replacing any file
+/// already there once the database is complete
+pub fn save(results: &Results, path: &Path) -> Result {
+    let tmp = crate::staging_path(path);
+
+    match fs::remove_file(&tmp) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            return Err(e).with_context(|| format!("Failed to remove stale file {:?}", tmp));
+        },
+        _ => (),
+    }
+
+    create(&tmp, results).inspect_err(|_| {
+        if let Err(e) = fs::remove_file(&tmp) {
+            error!("Failed to clean up {:?}: {:?}", tmp, e);
+        }
+    })?;
+
+    fs::rename(&tmp, path).with_context(|| format!("Failed to move database to {:?}", path))
+}
+
+fn create(path: &Path, results: &Results) -> Result {
+    let mut conn =
+        Connection::open(path).with_context(|| format!("Failed to create database {:?}", path))?;
+
+    let tx = conn.transaction().context("Failed to start transaction")?;
+    tx.execute_batch(SCHEMA).context("Failed to create tables")?;
+    write(&tx, results).with_context(|| format!("Failed to write database {:?}", path))?;
+    tx.commit().context("Failed to commit transaction")?;
+
+    conn.close()
+        .map_err(|(_, e)| e)
+        .with_context(|| format!("Failed to close database {:?}", path))
+}
+
+fn write(tx: &Transaction, results: &Results) -> rusqlite::Result<()> {
+    let header = &results.header;
+
+    let mut stmt = tx.prepare("INSERT INTO scan (key, value) VALUES (?1, ?2)")?;
+    for (key, value) in [
+        ("format", header.format.clone()),
+        ("version", header.version.to_string()),
+        ("latke_version", header.latke_version.clone()),
+        ("created", header.created.to_string()),
+        ("algorithm", header.algorithm.to_string()),
+    ] {
+        stmt.execute(params![key, value])?;
+    }
+
+    let mut stmt = tx.prepare("INSERT INTO roots (position, path) VALUES (?1, ?2)")?;
+    for (i, root) in (0_u32..).zip(&header.roots) {
+        stmt.execute(params![i, path_value(root)])?;
+    }
+
+    let mut hashes: HashMap<(&str, &Hash), HashInfo> = HashMap::default();
+
+    for item in &results.items {
+        if let (Some(kind), Some(hash)) = (kind(item.ty), item.hash.as_ref()) {
+            let info = hashes.entry((kind, hash)).or_default();
+            info.size = item.size;
+            info.paths += 1;
+            info.inodes.insert((item.device, item.inode));
+        }
+    }
+
+    let mut ids = HashMap::default();
+    let mut stmt = tx.prepare(
+        "INSERT INTO hashes (kind, hash, size, paths, copies) VALUES (?1, ?2, ?3, ?4, ?5)",
+    )?;
+
+    for ((kind, hash), info) in &hashes {
+        stmt.execute(params![
+            kind,
+            hash.to_string(),
+            info.size,
+            info.paths,
+            info.inodes.len()
+        ])?;
+        ids.insert((*kind, *hash), tx.last_insert_rowid());
+    }
+
+    let mut files = tx.prepare(
+        "INSERT INTO files (path, type, size, mtime, mtime_nsec, inode, device, hash_id)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+    )?;
+    let mut dirs = tx.prepare(
+        "INSERT INTO directories (path, size, mtime, mtime_nsec, inode, device, hash_id)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+    )?;
+
+    for item in &results.items {
+        let ItemRecord {
+            ref path,
+            ty,
+            size,
+            mtime,
+            mtime_nsec,
+            inode,
+            device,
+            ref hash,
+        } = *item;
+
+        let path = path_value(path);
+        let hash_id = kind(ty)
+            .zip(hash.as_ref())
+            .and_then(|k| ids.get(&k).copied());
+
+        if ty == ItemType::Directory {
+            dirs.execute(params![path, size, mtime, mtime_nsec, inode, device, hash_id])?;
+        } else {
+            files.execute(params![
+                path,
+                type_name(ty),
+                size,
+                mtime,
+                mtime_nsec,
+                inode,
+                device,
+                hash_id
+            ])?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Paths are stored as text where possible, and otherwise as a blob of their
+/// raw bytes, so that every row refers to the exact file found
+fn path_value(path: &Path) -> ToSqlOutput<'_> {
+    ToSqlOutput::Borrowed(match path.to_str() {
+        Some(s) => ValueRef::Text(s.as_bytes()),
+        None => ValueRef::Blob(path.as_os_str().as_bytes()),
+    })
+}
+
+/// The kind of duplicate group items of this type belong to, matching
+/// [`report::Kind`](crate::report::Kind)
+fn kind(ty: ItemType) -> Option<&'static str> {
+    match ty {
+        ItemType::File => Some("file"),
+        ItemType::Directory => Some("directory"),
+        ItemType::Symlink => Some("symlink"),
+        ItemType::Fifo | ItemType::Socket | ItemType::BlockDevice | ItemType::CharDevice => None,
+    }
+}
+
+fn type_name(ty: ItemType) -> &'static str {
+    match ty {
+        ItemType::File => "file",
+        ItemType::Directory => "directory",
+        ItemType::Symlink => "symlink",
+        ItemType::Fifo => "fifo",
+        ItemType::Socket => "socket",
+        ItemType::BlockDevice => "block-device",
+        ItemType::CharDevice => "char-device",
+    }
+}
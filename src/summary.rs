@@ -0,0 +1,117 @@
+//! Statistics printed at the end of a run
+
+use std::{
+    io,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    progress::human_bytes,
+    report::{Group, Kind},
+    Worker,
+};
+
+/// Counts of items left out of the search, by reason
+#[derive(Debug, Default)]
+pub struct Skipped {
+    pub cross_device: AtomicUsize,
+    pub excluded: AtomicUsize,
+    pub too_deep: AtomicUsize,
+    pub too_shallow: AtomicUsize,
+    pub denied: AtomicUsize,
+    pub failed: AtomicUsize,
+    pub symlinks: AtomicUsize,
+}
+
+impl Skipped {
+    /// Count an item that could not be processed due to an error
+    pub fn error(&self, err: &anyhow::Error) {
+        let denied = err.chain().any(|c| {
+            c.downcast_ref::<io::Error>()
+                .is_some_and(|e| e.kind() == io::ErrorKind::PermissionDenied)
+        });
+
+        if denied { &self.denied } else { &self.failed }.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Print what was searched, what was skipped and how much space duplicates
+/// take up to stderr
+pub fn print(worker: &Worker, groups: &[Group], elapsed: Duration) {
+    let Worker {
+        ref files_done,
+        ref dirs_done,
+        ref total_special,
+        ref inodes,
+        ref empty_files,
+        ref bytes_hashed,
+        ref skipped,
+        ..
+    } = *worker;
+
+    // Files reached more than once, whether through symlinks or hardlinks,
+    // share an inode and are only counted once here
+    let size: u64 = inodes.iter().map(|e| e.value()[0].1.len()).sum();
+    let secs = elapsed.as_secs_f64();
+    let read = bytes_hashed.load(Ordering::Relaxed);
+    #[allow(
+        clippy::cast_precision_loss,
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss
+    )]
+    let rate = if secs > 0.0 {
+        (read as f64 / secs) as u64
+    } else {
+        0
+    };
+
+    eprintln!(
+        "Scanned {} file(s) and {} dir(s) totalling {} in {:.1}s",
+        files_done.load(Ordering::Relaxed),
+        dirs_done.load(Ordering::Relaxed),
+        human_bytes(size),
+        secs
+    );
+    eprintln!("Read {} ({} bytes, {}/s)", human_bytes(read), read, human_bytes(rate));
+
+    let reasons = [
+        (skipped.cross_device.load(Ordering::Relaxed), "on another filesystem"),
+        (skipped.excluded.load(Ordering::Relaxed), "excluded"),
+        (skipped.too_deep.load(Ordering::Relaxed), "past --max-depth"),
+        (skipped.too_shallow.load(Ordering::Relaxed), "above --min-depth"),
+        (skipped.denied.load(Ordering::Relaxed), "permission denied"),
+        (skipped.failed.load(Ordering::Relaxed), "other errors"),
+        (total_special.load(Ordering::Relaxed), "special files"),
+        (skipped.symlinks.load(Ordering::Relaxed), "symlinks"),
+    ];
+
+    if reasons.iter().any(|(n, _)| *n != 0) {
+        let reasons: Vec<_> = reasons
+            .iter()
+            .filter(|(n, _)| *n != 0)
+            .map(|(n, r)| format!("{} {}", n, r))
+            .collect();
+
+        eprintln!("Skipped {}", reasons.join(", "));
+    }
+
+    // Directory groups overlap with the groups of the files inside them, so
+    // only file groups are counted towards the space that can be reclaimed
+    let wasted: u64 = groups
+        .iter()
+        .filter(|g| g.kind == Kind::File)
+        .map(|g| g.size * (g.copies as u64).saturating_sub(1))
+        .sum();
+
+    eprintln!(
+        "Found {} duplicate group(s), with {} ({} bytes) reclaimable",
+        groups.len(),
+        human_bytes(wasted),
+        wasted
+    );
+
+    if !empty_files.is_empty() {
+        eprintln!("Found {} empty file(s)", empty_files.len());
+    }
+}
@@ -0,0 +1,131 @@
+use std::{
+    fs,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+};
+
+use anyhow::Context;
+use clap::ArgEnum;
+use topograph::prelude::*;
+
+use crate::{
+    dev_id::DevId,
+    digest::{Algorithm, Hash},
+    dir,
+    hash::HashMap,
+    ignores, Job, Meta, Result, Worker,
+};
+
+/// How symbolic links encountered during the search are treated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum Policy {
+    /// Ignore symlinks entirely
+    Skip,
+    /// Search the file or directory a symlink points to in place of the link
+    Follow,
+    /// Hash the path a symlink points to, so that identical links are
+    /// reported as duplicates
+    HashTargetPath,
+}
+
+/// Replace the search paths with their canonical paths if links are followed,
+/// since followed links are tracked by their canonical targets, and a target
+/// that has already been searched must be recognized as such
+pub fn resolve_roots(policy: Policy, paths: &mut [(PathBuf, Meta)]) -> Result {
+    if policy != Policy::Follow {
+        return Ok(());
+    }
+
+    for (path, _) in paths {
+        *path = fs::canonicalize(&*path)
+            .with_context(|| format!("Failed to resolve search path {:?}", path))?;
+    }
+
+    Ok(())
+}
+
+/// Schedule the canonical target of a symlink to be searched.  The search
+/// paths are canonicalized before walking when links are followed, so every
+/// path walked is canonical, and a link pointing back into a directory that
+/// has already been searched is only visited once.  The target is searched at
+/// the same depth as the link, and is subject to `--min-depth` like any other
+/// item found there.
+pub fn follow(
+    path: PathBuf,
+    root_id: Option<DevId>,
+    depth: usize,
+    handle: crate::Handle,
+    worker: impl AsRef<Worker>,
+) -> Result {
+    let worker = worker.as_ref();
+    let Worker {
+        ref seen,
+        ref link_targets,
+        ..
+    } = *worker;
+
+    let target =
+        fs::canonicalize(&path).with_context(|| format!("Failed to resolve symlink {:?}", path))?;
+    let meta =
+        fs::metadata(&target).with_context(|| format!("Failed to stat target of {:?}", path))?;
+
+    // The target may be anywhere, so it is subject to the ignore files around
+    // it rather than those around the link
+    let ignores = if worker.ignore_files {
+        ignores::Stack::for_root(&target)
+    } else {
+        ignores::Stack::default()
+    };
+
+    if dir::excluded(&target, meta.is_dir(), &ignores, worker) {
+        worker.skipped.excluded.fetch_add(1, Ordering::Relaxed);
+        return Ok(());
+    }
+
+    link_targets.insert(path, target.clone());
+
+    if seen.contains(&target) {
+        return Ok(());
+    }
+
+    if let Some(job) = Job::path(target, meta, root_id, depth, ignores, worker)? {
+        handle.push(job);
+    }
+
+    Ok(())
+}
+
+/// Hash the target path of a symlink, without following it
+pub fn hash_target(path: PathBuf, meta: Meta, worker: impl AsRef<Worker>) -> Result {
+    let Worker {
+        algorithm,
+        ref hash_for_link,
+        ref link_hashes,
+        ..
+    } = *worker.as_ref();
+
+    let hash = target_hash(&path, algorithm)?;
+
+    if hash_for_link.insert(path.clone(), hash.clone()).is_none() {
+        assert!(
+            link_hashes
+                .entry(hash)
+                .or_insert_with(HashMap::default)
+                .insert(path, meta)
+                .is_none()
+        );
+    }
+
+    Ok(())
+}
+
+/// Hash the path a symlink points to
+pub fn target_hash(path: &Path, algorithm: Algorithm) -> Result<Hash> {
+    let target = fs::read_link(path).with_context(|| format!("Failed to read symlink {:?}", path))?;
+
+    let mut hasher = algorithm.hasher();
+    hasher.update(target.as_os_str().as_bytes());
+
+    Ok(hasher.finalize())
+}
@@ -0,0 +1,125 @@
+//! Rechecking saved hashes against the files on disk
+
+use std::{fs, io, os::unix::fs::MetadataExt};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    digest::{Algorithm, Hash},
+    file,
+    hash::HashMap,
+    results::{ItemRecord, ItemType, Results},
+    symlink, Result,
+};
+
+/// The outcome of checking a single path
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Status {
+    Ok,
+    Missing,
+    /// The contents and metadata have both changed, suggesting the file was
+    /// deliberately modified
+    Changed,
+    /// The contents have changed but the size and modification time have
+    /// not, suggesting corruption
+    Corrupted,
+    Failed,
+}
+
+/// Rehash every file and symlink with a saved hash, printing each one that no
+/// longer matches.  Fails if any do not match.
+pub fn run(results: &Results, block_size: usize) -> Result {
+    let algorithm = results.header.algorithm;
+    let mut counts: HashMap<_, usize> = HashMap::default();
+    // Hardlinks to the same inode only need to be read once
+    let mut by_inode: HashMap<(u64, u64), Hash> = HashMap::default();
+
+    for item in &results.items {
+        let Some(ref expected) = item.hash else {
+            continue;
+        };
+
+        if !matches!(item.ty, ItemType::File | ItemType::Symlink) {
+            continue;
+        }
+
+        let status = match check(item, expected, algorithm, block_size, &mut by_inode) {
+            Ok(s) => s,
+            Err(e) => {
+                println!("error: {}: {:?}", item.path.display(), e);
+                Status::Failed
+            },
+        };
+
+        match status {
+            Status::Ok | Status::Failed => (),
+            Status::Missing => println!("missing: {}", item.path.display()),
+            Status::Changed => println!("changed: {}", item.path.display()),
+            Status::Corrupted => println!("corrupted: {}", item.path.display()),
+        }
+
+        *counts.entry(status).or_default() += 1;
+    }
+
+    let count = |s| counts.get(&s).copied().unwrap_or(0);
+    let total: usize = counts.values().sum();
+
+    eprintln!(
+        "Verified {} path(s): {} ok, {} missing, {} changed, {} corrupted, {} failed",
+        total,
+        count(Status::Ok),
+        count(Status::Missing),
+        count(Status::Changed),
+        count(Status::Corrupted),
+        count(Status::Failed),
+    );
+
+    if count(Status::Ok) != total {
+        bail!("{} path(s) failed verification", total - count(Status::Ok));
+    }
+
+    Ok(())
+}
+
+fn check(
+    item: &ItemRecord,
+    expected: &Hash,
+    algorithm: Algorithm,
+    block_size: usize,
+    by_inode: &mut HashMap<(u64, u64), Hash>,
+) -> Result<Status> {
+    let meta = match fs::symlink_metadata(&item.path) {
+        Ok(m) => m,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Status::Missing),
+        Err(e) => return Err(e).with_context(|| format!("Failed to stat {:?}", item.path)),
+    };
+
+    let hash = match item.ty {
+        ItemType::File if meta.is_file() => {
+            let inode = (meta.dev(), meta.ino());
+
+            if let Some(h) = by_inode.get(&inode) {
+                h.clone()
+            } else {
+                let h = file::hash_contents(&item.path, algorithm, block_size, None)?;
+                by_inode.insert(inode, h.clone());
+                h
+            }
+        },
+        ItemType::Symlink if meta.is_symlink() => symlink::target_hash(&item.path, algorithm)?,
+        // The path has been replaced with something else entirely
+        ItemType::File | ItemType::Symlink => return Ok(Status::Changed),
+        _ => unreachable!(),
+    };
+
+    Ok(if hash == *expected {
+        Status::Ok
+    } else if meta.len() == item.size
+        && meta.mtime() == item.mtime
+        && meta.mtime_nsec() == item.mtime_nsec
+    {
+        Status::Corrupted
+    } else {
+        Status::Changed
+    })
+}